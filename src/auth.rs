@@ -0,0 +1,197 @@
+use std::{sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use argon2::{
+    password_hash::{rand_core::{OsRng, RngCore}, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Query},
+    http::{header::{AUTHORIZATION, WWW_AUTHENTICATE}, request::Parts},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use crate::{config::Config, storage, user::Account, error::AppError};
+
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 30); // 30d
+
+/// The session token signing key, held in the app state
+#[derive(Clone)]
+pub struct Keys {
+    secret: Arc<[u8]>,
+}
+
+impl Keys {
+    /// Loads the key, creating one on first run.
+    /// Kept on disk so sessions survive a restart.
+    pub async fn load(path: &str) -> Keys {
+        let secret = match tokio::fs::read_to_string(path).await {
+            Ok(content) => hex::decode(content.trim()).expect("corrupt session.key"),
+            Err(_) => {
+                let mut secret = vec![0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                storage::write_atomic(path, hex::encode(&secret).as_bytes()).await.expect("could not write session.key");
+                secret
+            }
+        };
+        Keys { secret: secret.into() }
+    }
+
+    fn sign(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Token format: "{user_id}.{token_generation}.{expires_unix}.{hex hmac}"
+    pub fn issue_token(&self, user: &Account) -> String {
+        let expires = (SystemTime::now() + TOKEN_LIFETIME).duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let payload = format!("{}.{}.{expires}", user.id, user.token_generation);
+        let signature = hex::encode(self.sign(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Returns the user id and token generation if the token is authentic and unexpired
+    pub fn verify_token(&self, token: &str) -> Option<(u32, u32)> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        self.sign(payload).verify_slice(&hex::decode(signature).ok()?).ok()?;
+
+        let mut parts = payload.split('.');
+        let (user_id, generation, expires) = (parts.next()?, parts.next()?, parts.next()?);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if parts.next().is_some() || expires.parse::<u64>().ok()? < now {
+            return None;
+        }
+        Some((user_id.parse().ok()?, generation.parse().ok()?))
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(hash) else { return false; };
+    Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok()
+}
+
+/// True for passwords saved before hashing was introduced
+pub fn is_legacy_password(stored: &str) -> bool {
+    PasswordHash::new(stored).is_err()
+}
+
+/// Extractor for endpoints that need a logged in user.
+/// Reads "Authorization: Bearer <token>", or "Basic" credentials for reader apps, and loads the owner's
+/// account; handlers that need the titles load them from it.
+pub struct AuthUser(pub Account);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+    Keys: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let header = parts.headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| AppError::Auth("missing bearer token".to_string()))?;
+        if let Some(credentials) = header.strip_prefix("Basic ") {
+            return basic_auth(credentials.trim()).map(AuthUser);
+        }
+        let token = header.strip_prefix("Bearer ")
+            .ok_or_else(|| AppError::Auth("missing bearer token".to_string()))?;
+        token_user(&Keys::from_ref(state), token.trim()).map(AuthUser)
+    }
+}
+
+fn token_user(keys: &Keys, token: &str) -> Result<Account, AppError> {
+    let (user_id, generation) = keys.verify_token(token).ok_or_else(|| AppError::Auth("invalid or expired token".to_string()))?;
+    let user = Account::from_id(user_id).map_err(|_| AppError::Auth("account no longer exists".to_string()))?;
+    // the password changed since
+    if user.token_generation != generation {
+        return Err(AppError::Auth("token revoked".to_string()));
+    }
+    Ok(user)
+}
+
+fn basic_auth(credentials: &str) -> Result<Account, AppError> {
+    let invalid = || AppError::Auth("invalid basic credentials".to_string());
+    let decoded = STANDARD.decode(credentials).ok().and_then(|bytes| String::from_utf8(bytes).ok()).ok_or_else(invalid)?;
+    let (username, password) = decoded.split_once(':').ok_or_else(invalid)?;
+    let user = Account::from(username).map_err(|_| invalid())?;
+    if !user.check_password(password) {
+        return Err(invalid());
+    }
+    Ok(user)
+}
+
+/// AuthUser for OPDS clients, a failed login asks for basic credentials so the app shows its prompt
+pub struct OpdsUser(pub Account);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for OpdsUser
+where
+    S: Send + Sync,
+    Keys: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match AuthUser::from_request_parts(parts, state).await {
+            Ok(AuthUser(user)) => Ok(OpdsUser(user)),
+            Err(e) => Err(([(WWW_AUTHENTICATE, "Basic realm=\"md_api\"")], e).into_response()),
+        }
+    }
+}
+
+/// AuthUser that also takes `?token=`, browsers can't set headers on an EventSource
+pub struct StreamUser(pub Account);
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for StreamUser
+where
+    S: Send + Sync,
+    Keys: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = Query::<TokenQuery>::from_request_parts(parts, state).await.ok().and_then(|Query(query)| query.token);
+        let Some(token) = token else {
+            return AuthUser::from_request_parts(parts, state).await.map(|AuthUser(user)| StreamUser(user));
+        };
+        token_user(&Keys::from_ref(state), token.trim()).map(StreamUser)
+    }
+}
+
+/// Like AuthUser, but only for usernames listed in the `admins` setting ($MD_API_ADMINS)
+pub struct AdminUser;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminUser
+where
+    S: Send + Sync,
+    Keys: FromRef<S>,
+    Arc<Config>: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AuthUser(user) = AuthUser::from_request_parts(parts, state).await?;
+        let config = Arc::<Config>::from_ref(state);
+        if !config.admins.contains(&user.username) {
+            return Err(AppError::Auth("admin only".to_string()));
+        }
+        Ok(AdminUser)
+    }
+}
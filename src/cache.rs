@@ -0,0 +1,215 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicU64, Ordering}, Mutex, OnceLock},
+    time::SystemTime,
+};
+use axum::body::Bytes;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::{error::Res, proxy::ProxiedImage, storage};
+
+/// Counters since startup, served by /admin/cache
+#[derive(Serialize, Clone, Debug)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: u64,
+    pub budget: u64,
+}
+
+struct Entry {
+    size: u64,
+    used: u64, // key into Index.order
+}
+
+// least recently used first in `order`
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    bytes: u64,
+    clock: u64,
+}
+
+impl Index {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.used);
+            entry.used = self.clock;
+            self.order.insert(self.clock, key.to_string());
+        }
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        self.remove(&key);
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, Entry { size, used: self.clock });
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+
+    // keys to delete so `bytes` fits the budget again
+    fn evict(&mut self, budget: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.bytes > budget {
+            let Some((_, key)) = self.order.pop_first() else { break; };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry.size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// Proxied images on disk, content addressed by the SHA-256 of their URL.
+/// Files are "{content type}\n{bytes}" under `{dir}/{first 2 hex chars}/{hash}`.
+struct ImageCache {
+    dir: PathBuf,
+    budget: u64,
+    index: Mutex<Index>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+static CACHE: OnceLock<ImageCache> = OnceLock::new();
+
+fn key(url: &str) -> String {
+    hex::encode(Sha256::digest(url.as_bytes()))
+}
+
+impl ImageCache {
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(key)
+    }
+}
+
+/// Indexes what's already on disk, oldest files are evicted first
+pub async fn init(dir: &str, budget: u64) -> Res<()> {
+    storage::create_dir_if_missing(dir.to_string()).await?;
+    let dir = PathBuf::from(dir);
+    let scan_dir = dir.clone();
+    let mut files = tokio::task::spawn_blocking(move || scan(&scan_dir)).await??;
+    files.sort_by_key(|(modified, _, _)| *modified);
+
+    let mut index = Index::default();
+    for (_, key, size) in files {
+        index.insert(key, size);
+    }
+    let evicted = index.evict(budget);
+    println!("Image cache: {} files, {} bytes", index.entries.len(), index.bytes);
+
+    let cache = ImageCache { dir, budget, index: Mutex::new(index), hits: AtomicU64::new(0), misses: AtomicU64::new(0), evictions: AtomicU64::new(0) };
+    remove_files(&cache, evicted).await;
+    CACHE.set(cache).ok();
+    Ok(())
+}
+
+// (modified, key, size) of every cached file, leftovers from interrupted writes are removed
+fn scan(dir: &Path) -> Res<Vec<(SystemTime, String, u64)>> {
+    let mut files = Vec::new();
+    for shard in std::fs::read_dir(dir)?.filter_map(|entry| entry.ok()) {
+        let Ok(entries) = std::fs::read_dir(shard.path()) else { continue; };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".tmp") {
+                std::fs::remove_file(entry.path()).ok();
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else { continue; };
+            files.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), name, metadata.len()));
+        }
+    }
+    Ok(files)
+}
+
+async fn remove_files(cache: &ImageCache, keys: Vec<String>) {
+    cache.evictions.fetch_add(keys.len() as u64, Ordering::Relaxed);
+    for key in keys {
+        tokio::fs::remove_file(cache.path(&key)).await.ok();
+    }
+}
+
+/// The cached image for `url`, None on a miss or if the cache isn't set up
+pub async fn get(url: &str) -> Option<ProxiedImage> {
+    let cache = CACHE.get()?;
+    let key = key(url);
+    let known = cache.index.lock().unwrap().entries.contains_key(&key);
+    let image = if known { read(&cache.path(&key)).await } else { None };
+
+    let Some(image) = image else {
+        if known {
+            cache.index.lock().unwrap().remove(&key);
+        }
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+        return None;
+    };
+    cache.index.lock().unwrap().touch(&key);
+    cache.hits.fetch_add(1, Ordering::Relaxed);
+
+    // so the order survives a restart
+    let path = cache.path(&key);
+    tokio::task::spawn_blocking(move || {
+        std::fs::File::options().append(true).open(path).and_then(|file| file.set_modified(SystemTime::now())).ok();
+    });
+    Some(image)
+}
+
+async fn read(path: &Path) -> Option<ProxiedImage> {
+    let bytes = Bytes::from(tokio::fs::read(path).await.ok()?);
+    let split = bytes.iter().position(|b| *b == b'\n')?;
+    let content_type = String::from_utf8(bytes[..split].to_vec()).ok()?;
+    Some(ProxiedImage { content_type, bytes: bytes.slice(split + 1..) })
+}
+
+/// Stores an image, evicting the least recently used ones past the budget.
+/// Failures are only logged, the cache is an optimisation.
+pub async fn put(url: &str, image: &ProxiedImage) {
+    let Some(cache) = CACHE.get() else { return; };
+    let key = key(url);
+    if let Err(e) = write(cache, &key, image).await {
+        println!("Could not cache {url}: {e}");
+        return;
+    }
+    let size = (image.content_type.len() + 1 + image.bytes.len()) as u64;
+    let evicted = {
+        let mut index = cache.index.lock().unwrap();
+        index.insert(key, size);
+        index.evict(cache.budget)
+    };
+    remove_files(cache, evicted).await;
+}
+
+async fn write(cache: &ImageCache, key: &str, image: &ProxiedImage) -> Res<()> {
+    storage::create_dir_if_missing(cache.dir.join(&key[..2]).to_string_lossy().to_string()).await?;
+    let mut content = Vec::with_capacity(image.content_type.len() + 1 + image.bytes.len());
+    content.extend_from_slice(image.content_type.as_bytes());
+    content.push(b'\n');
+    content.extend_from_slice(&image.bytes);
+    storage::write_atomic(&cache.path(key).to_string_lossy(), &content).await
+}
+
+pub fn stats() -> Option<Stats> {
+    let cache = CACHE.get()?;
+    let index = cache.index.lock().unwrap();
+    Some(Stats {
+        hits: cache.hits.load(Ordering::Relaxed),
+        misses: cache.misses.load(Ordering::Relaxed),
+        evictions: cache.evictions.load(Ordering::Relaxed),
+        entries: index.entries.len(),
+        bytes: index.bytes,
+        budget: cache.budget,
+    })
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use crate::user::Chapter;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Listed {
+    pub index: u32,
+    pub t: String,
+    pub s: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Renamed {
+    pub index: u32,
+    pub old_index: u32,
+    pub from: String,
+    pub to: String,
+}
+
+/// Same chapter text under a new suffix: the site deleted and uploaded it again
+#[derive(Serialize, Clone, Debug)]
+pub struct Reuploaded {
+    pub index: u32,
+    pub old_index: u32,
+    pub t: String,
+    pub old_s: String,
+    pub s: String,
+}
+
+/// What an update changed in a chapter list. Chapters are identified by their suffix (`Chapter.s`),
+/// `index` is the position in the new list and `old_index` the one in the old list.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ChapterDiff {
+    pub added: Vec<Listed>,
+    pub removed: Vec<Listed>, // with their old index
+    pub renamed: Vec<Renamed>,
+    pub reuploaded: Vec<Reuploaded>,
+    pub reordered: bool, // chapters present in both lists changed order
+    #[serde(skip)]
+    pub remap: Vec<Option<u32>>, // old index -> new index, None once removed
+}
+
+impl ChapterDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renamed.is_empty() && self.reuploaded.is_empty() && !self.reordered
+    }
+
+    /// True if any old index now points at a different chapter
+    pub fn moves_indexes(&self) -> bool {
+        (0u32..).zip(&self.remap).any(|(old, new)| *new != Some(old))
+    }
+
+    /// Where a reader positioned on `old` continues: the chapter itself, or the closest one before it that survived
+    pub fn position(&self, old: u32) -> u32 {
+        let last = (old as usize).min(self.remap.len().saturating_sub(1));
+        self.remap.get(..=last).unwrap_or_default().iter().rev().find_map(|new| *new).unwrap_or(0)
+    }
+}
+
+/// Reconciles `old` with a freshly scraped `new` list
+pub fn diff(old: &[Chapter], new: &[Chapter]) -> ChapterDiff {
+    let mut diff = ChapterDiff { remap: vec![None; old.len()], ..ChapterDiff::default() };
+    let mut old_by_suffix: HashMap<&str, u32> = HashMap::new();
+    for (index, chapter) in (0u32..).zip(old) {
+        old_by_suffix.entry(chapter.s.as_str()).or_insert(index);
+    }
+
+    let mut unmatched_new = Vec::new();
+    for (index, chapter) in (0u32..).zip(new) {
+        let Some(old_index) = old_by_suffix.remove(chapter.s.as_str()) else {
+            unmatched_new.push(index);
+            continue;
+        };
+        diff.remap[old_index as usize] = Some(index);
+        let before = &old[old_index as usize];
+        if before.t != chapter.t {
+            diff.renamed.push(Renamed { index, old_index, from: before.t.clone(), to: chapter.t.clone() });
+        }
+    }
+
+    let kept: Vec<u32> = diff.remap.iter().flatten().copied().collect();
+    diff.reordered = kept.windows(2).any(|pair| pair[0] > pair[1]);
+
+    // an unmatched old chapter with the same text as an unmatched new one was uploaded again
+    let mut unmatched_old: Vec<u32> = (0u32..).zip(&diff.remap).filter(|(_, new)| new.is_none()).map(|(old, _)| old).collect();
+    for index in unmatched_new {
+        let chapter = &new[index as usize];
+        match unmatched_old.iter().position(|old_index| old[*old_index as usize].t == chapter.t) {
+            Some(position) => {
+                let old_index = unmatched_old.remove(position);
+                diff.remap[old_index as usize] = Some(index);
+                diff.reuploaded.push(Reuploaded {
+                    index,
+                    old_index,
+                    t: chapter.t.clone(),
+                    old_s: old[old_index as usize].s.clone(),
+                    s: chapter.s.clone(),
+                });
+            }
+            None => diff.added.push(Listed { index, t: chapter.t.clone(), s: chapter.s.clone() }),
+        }
+    }
+    for old_index in unmatched_old {
+        let chapter = &old[old_index as usize];
+        diff.removed.push(Listed { index: old_index, t: chapter.t.clone(), s: chapter.s.clone() });
+    }
+
+    diff
+}
+
+/// Known chapters take the fresh metadata of `new`, keeping what this scrape could not read.
+/// Page counts only carry over for the same upload, re-uploads are counted again.
+pub fn carry_over(old: &[Chapter], new: &mut [Chapter], diff: &ChapterDiff) {
+    for (old_index, (old, new_index)) in (0u32..).zip(old.iter().zip(&diff.remap)) {
+        let Some(new_index) = new_index else { continue; };
+        let chapter = &mut new[*new_index as usize];
+        if !diff.reuploaded.iter().any(|reuploaded| reuploaded.old_index == old_index) {
+            chapter.i = old.i;
+            chapter.counted = old.counted;
+        }
+        chapter.released = chapter.released.take().or_else(|| old.released.clone());
+        chapter.group = chapter.group.take().or_else(|| old.group.clone());
+        chapter.language = chapter.language.take().or_else(|| old.language.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(s: &str, t: &str) -> Chapter {
+        Chapter { t: t.to_string(), s: s.to_string(), i: 0, counted: false, number: None, volume: None, released: None, group: None, language: None }
+    }
+
+    fn list(chapters: &[(&str, &str)]) -> Vec<Chapter> {
+        chapters.iter().map(|(s, t)| chapter(s, t)).collect()
+    }
+
+    #[test]
+    fn unchanged_list_is_empty() {
+        let old = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2")]);
+        let diff = diff(&old, &old);
+        assert!(diff.is_empty());
+        assert!(!diff.moves_indexes());
+        assert_eq!(diff.remap, vec![Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn appended_chapter_keeps_indexes() {
+        let old = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2")]);
+        let new = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2"), ("c3", "Chapter 3")]);
+        let diff = diff(&old, &new);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!((diff.added[0].index, diff.added[0].s.as_str()), (2, "c3"));
+        assert!(!diff.moves_indexes());
+    }
+
+    #[test]
+    fn inserted_chapter_shifts_later_ones() {
+        let old = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2")]);
+        let new = list(&[("c1", "Chapter 1"), ("c1-5", "Chapter 1.5"), ("c2", "Chapter 2")]);
+        let diff = diff(&old, &new);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].index, 1);
+        assert_eq!(diff.remap, vec![Some(0), Some(2)]);
+        assert!(diff.moves_indexes());
+        assert!(!diff.reordered);
+        assert_eq!(diff.position(1), 2);
+    }
+
+    #[test]
+    fn same_text_under_a_new_suffix_is_a_reupload() {
+        let old = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2")]);
+        let new = list(&[("c1", "Chapter 1"), ("c2-v2", "Chapter 2")]);
+        let diff = diff(&old, &new);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.reuploaded.len(), 1);
+        let reuploaded = &diff.reuploaded[0];
+        assert_eq!((reuploaded.old_index, reuploaded.index), (1, 1));
+        assert_eq!((reuploaded.old_s.as_str(), reuploaded.s.as_str()), ("c2", "c2-v2"));
+        assert_eq!(diff.remap, vec![Some(0), Some(1)]);
+        assert!(!diff.moves_indexes());
+    }
+
+    #[test]
+    fn removed_chapter_moves_readers_back() {
+        let old = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2"), ("c3", "Chapter 3")]);
+        let new = list(&[("c1", "Chapter 1"), ("c3", "Chapter 3")]);
+        let diff = diff(&old, &new);
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!((diff.removed[0].index, diff.removed[0].s.as_str()), (1, "c2"));
+        assert_eq!(diff.remap, vec![Some(0), None, Some(1)]);
+        // a reader on the removed chapter continues from the one before it
+        assert_eq!(diff.position(1), 0);
+        assert_eq!(diff.position(2), 1);
+    }
+
+    #[test]
+    fn renamed_and_reordered_chapters() {
+        let old = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2")]);
+        let new = list(&[("c2", "Chapter 2: Title"), ("c1", "Chapter 1")]);
+        let diff = diff(&old, &new);
+        assert_eq!(diff.renamed.len(), 1);
+        assert_eq!((diff.renamed[0].from.as_str(), diff.renamed[0].to.as_str()), ("Chapter 2", "Chapter 2: Title"));
+        assert!(diff.reordered);
+        assert_eq!(diff.remap, vec![Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn carry_over_keeps_what_the_scrape_lacks() {
+        let mut old = list(&[("c1", "Chapter 1")]);
+        old[0].i = 12;
+        old[0].counted = true;
+        old[0].released = Some("2024-01-01".to_string());
+        old[0].group = Some("Group".to_string());
+        let mut new = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2")]);
+        new[0].group = Some("New Group".to_string());
+        let diff = diff(&old, &new);
+        carry_over(&old, &mut new, &diff);
+        assert_eq!(new[0].i, 12);
+        assert!(new[0].counted);
+        assert_eq!(new[0].released.as_deref(), Some("2024-01-01"));
+        assert_eq!(new[0].group.as_deref(), Some("New Group"));
+        assert_eq!(new[1].i, 0);
+    }
+
+    #[test]
+    fn reupload_is_counted_again() {
+        let mut old = list(&[("c1", "Chapter 1"), ("c2", "Chapter 2")]);
+        old[0].i = 12;
+        old[1].i = 20;
+        old[0].counted = true;
+        old[1].counted = true;
+        old[1].group = Some("Group".to_string());
+        let mut new = list(&[("c1", "Chapter 1"), ("c2-v2", "Chapter 2")]);
+        let diff = diff(&old, &new);
+        carry_over(&old, &mut new, &diff);
+        assert_eq!(new[0].i, 12);
+        assert_eq!((new[1].i, new[1].counted), (0, false));
+        assert_eq!(new[1].group.as_deref(), Some("Group"));
+    }
+}
@@ -0,0 +1,435 @@
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use axum::{extract::FromRef, http::HeaderValue};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use crate::{auth::Keys, downloads, http, page_counts, retention, scheduler, storage::Storage, web::Fetcher};
+
+const DEFAULT_PATH: &str = "./md_api.toml";
+const ENV_PREFIX: &str = "MD_API_";
+
+/// Server settings. Later layers win: defaults, the TOML file, `MD_API_*` env vars, then CLI flags.
+/// Env vars are named after the key: `bind` → `MD_API_BIND`, `[cache] budget_mb` → `MD_API_CACHE_BUDGET_MB`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    pub admins: Vec<String>, // usernames allowed on /admin, "a,b" in env vars
+    pub shutdown_grace_secs: u64, // how long open requests and downloads get to finish
+    pub paths: Paths,
+    pub http: Http,
+    pub proxy: Proxy,
+    pub cache: Cache,
+    pub scheduler: Scheduler,
+    pub retention: Retention,
+    pub downloads: Downloads,
+    pub page_counts: PageCounts,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Paths {
+    pub titles: String,
+    pub covers: String,
+    pub cache: String,
+    pub sources: String,
+    pub database: String,
+    pub session_key: String,
+    pub legacy_db: String, // imported once into `database`
+    pub legacy_users: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub user_agent: String,
+    pub manganato_referer: String,
+    pub connect_timeout_secs: u64,
+    pub read_timeout_secs: u64, // for the response, then for each chunk of the body
+    pub retries: u32, // 0 gives up after the first failure
+    pub backoff_ms: u64, // before the first retry, doubled for each one after
+    pub max_backoff_secs: u64, // also caps a site's Retry-After
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Proxy {
+    pub max_image_mb: u64,
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    pub budget_mb: u64, // 0 disables the cache
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scheduler {
+    pub interval_secs: u64,
+    pub concurrency: usize,
+    pub per_host_delay_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Downloads {
+    pub concurrency: usize, // jobs downloading at the same time
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PageCounts {
+    pub concurrency: usize, // chapter pages scraped at the same time
+    pub delay_ms: u64, // between chapters of the background pass
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    pub budget_mb: u64,
+    pub keep_ahead: u32,
+    pub grace_secs: u64,
+    pub interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "0.0.0.0:3000".to_string(),
+            admins: Vec::new(),
+            shutdown_grace_secs: 30,
+            paths: Paths::default(),
+            http: Http::default(),
+            proxy: Proxy::default(),
+            cache: Cache::default(),
+            scheduler: Scheduler::default(),
+            retention: Retention::default(),
+            downloads: Downloads::default(),
+            page_counts: PageCounts::default(),
+        }
+    }
+}
+
+impl Default for Paths {
+    fn default() -> Paths {
+        Paths {
+            titles: "./public/titles".to_string(),
+            covers: "./public/covers".to_string(),
+            cache: "./public/cache".to_string(),
+            sources: "./public/sources".to_string(),
+            database: "./public/md_api.sqlite3".to_string(),
+            session_key: "./public/session.key".to_string(),
+            legacy_db: "./public/db.json".to_string(),
+            legacy_users: "./public/users".to_string(),
+        }
+    }
+}
+
+impl Default for Http {
+    fn default() -> Http {
+        let settings = http::Settings::default();
+        Http {
+            user_agent: "Mozilla/5.0".to_string(),
+            manganato_referer: "https://manganato.com/".to_string(),
+            connect_timeout_secs: settings.connect_timeout.as_secs(),
+            read_timeout_secs: settings.read_timeout.as_secs(),
+            retries: settings.retries,
+            backoff_ms: settings.backoff.as_millis() as u64,
+            max_backoff_secs: settings.max_backoff.as_secs(),
+        }
+    }
+}
+
+impl Default for Proxy {
+    fn default() -> Proxy {
+        Proxy { max_image_mb: 20, timeout_secs: 20, connect_timeout_secs: 5 }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache { budget_mb: 512 }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        let settings = scheduler::Settings::default();
+        Scheduler {
+            interval_secs: settings.interval.as_secs(),
+            concurrency: settings.concurrency,
+            per_host_delay_secs: settings.per_host_delay.as_secs(),
+        }
+    }
+}
+
+impl Default for Downloads {
+    fn default() -> Downloads {
+        Downloads { concurrency: downloads::Settings::default().concurrency }
+    }
+}
+
+impl Default for PageCounts {
+    fn default() -> PageCounts {
+        let settings = page_counts::Settings::default();
+        PageCounts { concurrency: settings.concurrency, delay_ms: settings.delay.as_millis() as u64 }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        let policy = retention::Policy::default();
+        Retention {
+            budget_mb: policy.budget / MB,
+            keep_ahead: policy.keep_ahead,
+            grace_secs: policy.grace.as_secs(),
+            interval_secs: policy.interval.as_secs(),
+        }
+    }
+}
+
+const MB: u64 = 1024 * 1024;
+
+impl Proxy {
+    pub fn max_image_bytes(&self) -> usize {
+        (self.max_image_mb * MB) as usize
+    }
+}
+
+impl Cache {
+    pub fn budget_bytes(&self) -> u64 {
+        self.budget_mb * MB
+    }
+}
+
+impl Http {
+    pub fn settings(&self) -> http::Settings {
+        http::Settings {
+            connect_timeout: Duration::from_secs(self.connect_timeout_secs),
+            read_timeout: Duration::from_secs(self.read_timeout_secs),
+            retries: self.retries,
+            backoff: Duration::from_millis(self.backoff_ms),
+            max_backoff: Duration::from_secs(self.max_backoff_secs),
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn settings(&self) -> scheduler::Settings {
+        scheduler::Settings {
+            interval: Duration::from_secs(self.interval_secs),
+            concurrency: self.concurrency,
+            per_host_delay: Duration::from_secs(self.per_host_delay_secs),
+        }
+    }
+}
+
+impl Downloads {
+    pub fn settings(&self) -> downloads::Settings {
+        downloads::Settings { concurrency: self.concurrency }
+    }
+}
+
+impl PageCounts {
+    pub fn settings(&self) -> page_counts::Settings {
+        page_counts::Settings { concurrency: self.concurrency, delay: Duration::from_millis(self.delay_ms) }
+    }
+}
+
+impl Retention {
+    pub fn policy(&self) -> retention::Policy {
+        retention::Policy {
+            budget: self.budget_mb * MB,
+            keep_ahead: self.keep_ahead,
+            grace: Duration::from_secs(self.grace_secs),
+            interval: Duration::from_secs(self.interval_secs),
+        }
+    }
+}
+
+/// Shared with every handler through axum state, along with what is built from the config once at startup
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub storage: Storage,
+    pub web: Fetcher,
+    pub keys: Keys,
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Arc<Config> {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Storage {
+    fn from_ref(state: &AppState) -> Storage {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for Fetcher {
+    fn from_ref(state: &AppState) -> Fetcher {
+        state.web.clone()
+    }
+}
+
+impl FromRef<AppState> for Keys {
+    fn from_ref(state: &AppState) -> Keys {
+        state.keys.clone()
+    }
+}
+
+const USAGE: &str = "Usage: md_api [--config FILE] [--bind ADDR] [--set KEY=VALUE]...
+
+  --config FILE     TOML settings (default ./md_api.toml, or $MD_API_CONFIG)
+  --bind ADDR       listen address, same as --set bind=ADDR
+  --set KEY=VALUE   any setting, e.g. --set retention.budget_mb=4096
+
+Every setting can also be given as an env var: MD_API_BIND, MD_API_CACHE_BUDGET_MB, ...";
+
+/// Builds the config from the defaults, file, environment and `args` (without the program name)
+pub fn load(args: impl Iterator<Item = String>) -> Result<Config, String> {
+    let mut file = std::env::var(format!("{ENV_PREFIX}CONFIG")).ok();
+    let mut overrides: Vec<(String, String)> = Vec::new();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or_else(|| format!("{flag} needs a value\n\n{USAGE}"));
+        match arg.as_str() {
+            "--config" => file = Some(value("--config")?),
+            "--bind" => overrides.push(("bind".to_string(), value("--bind")?)),
+            "--set" => {
+                let setting = value("--set")?;
+                let (key, value) = setting.split_once('=').ok_or_else(|| format!("--set expects KEY=VALUE, got {setting:?}"))?;
+                overrides.push((key.trim().to_string(), value.trim().to_string()));
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unknown argument {arg:?}\n\n{USAGE}")),
+        }
+    }
+
+    let mut table = toml::Table::try_from(Config::default()).map_err(|e| e.to_string())?;
+    let defaults = table.clone();
+
+    // a missing default file is fine, a missing --config file isn't
+    let path = file.clone().unwrap_or_else(|| DEFAULT_PATH.to_string());
+    if file.is_some() || Path::new(&path).exists() {
+        let content = std::fs::read_to_string(&path).map_err(|e| format!("could not read {path}: {e}"))?;
+        let from_file: toml::Table = toml::from_str(&content).map_err(|e| format!("{path}: {e}"))?;
+        merge(&mut table, from_file);
+        println!("Loaded settings from {path}");
+    }
+
+    for (key, value) in env_keys(&defaults) {
+        if let Ok(env) = std::env::var(&value) {
+            set(&mut table, &defaults, &key, &env).map_err(|e| format!("{value}: {e}"))?;
+        }
+    }
+    for (key, value) in overrides {
+        set(&mut table, &defaults, &key, &value).map_err(|e| format!("--set {key}: {e}"))?;
+    }
+
+    let config: Config = table.try_into().map_err(|e| format!("invalid settings: {e}"))?;
+    config.validate()?;
+    Ok(config)
+}
+
+fn merge(into: &mut toml::Table, from: toml::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(into)), toml::Value::Table(from)) => merge(into, from),
+            (_, value) => { into.insert(key, value); }
+        }
+    }
+}
+
+// ("cache.budget_mb", "MD_API_CACHE_BUDGET_MB") for every setting
+fn env_keys(defaults: &toml::Table) -> Vec<(String, String)> {
+    let mut keys = Vec::new();
+    for (key, value) in defaults {
+        match value {
+            toml::Value::Table(section) => keys.extend(section.keys().map(|field| (
+                format!("{key}.{field}"),
+                format!("{ENV_PREFIX}{}_{}", key.to_uppercase(), field.to_uppercase()),
+            ))),
+            _ => keys.push((key.clone(), format!("{ENV_PREFIX}{}", key.to_uppercase()))),
+        }
+    }
+    keys
+}
+
+// parses `raw` as the type the default value has
+fn set(table: &mut toml::Table, defaults: &toml::Table, key: &str, raw: &str) -> Result<(), String> {
+    let (section, field) = match key.split_once('.') {
+        Some((section, field)) => (Some(section), field),
+        None => (None, key),
+    };
+    let default = match section {
+        Some(section) => defaults.get(section).and_then(|value| value.as_table()).and_then(|table| table.get(field)),
+        None => defaults.get(field).filter(|value| !value.is_table()),
+    }
+    .ok_or_else(|| format!("unknown setting {key:?}"))?;
+
+    let value = match default {
+        toml::Value::String(_) => toml::Value::String(raw.to_string()),
+        toml::Value::Integer(_) => toml::Value::Integer(raw.parse().map_err(|_| format!("expected a number, got {raw:?}"))?),
+        toml::Value::Boolean(_) => toml::Value::Boolean(raw.parse().map_err(|_| format!("expected true or false, got {raw:?}"))?),
+        toml::Value::Array(_) => toml::Value::Array(
+            raw.split(',').map(str::trim).filter(|item| !item.is_empty()).map(|item| toml::Value::String(item.to_string())).collect(),
+        ),
+        _ => return Err(format!("{key} can't be set this way")),
+    };
+    let target = match section {
+        Some(section) => table.entry(section).or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut().ok_or_else(|| format!("{section} is not a section"))?,
+        None => table,
+    };
+    target.insert(field.to_string(), value);
+    Ok(())
+}
+
+impl Config {
+    // everything wrong at once, so a bad file doesn't take several restarts to fix
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if self.bind.parse::<SocketAddr>().is_err() {
+            errors.push(format!("bind: {:?} is not an address like 0.0.0.0:3000", self.bind));
+        }
+        let paths = [
+            ("titles", &self.paths.titles), ("covers", &self.paths.covers), ("cache", &self.paths.cache),
+            ("sources", &self.paths.sources), ("database", &self.paths.database), ("session_key", &self.paths.session_key),
+            ("legacy_db", &self.paths.legacy_db), ("legacy_users", &self.paths.legacy_users),
+        ];
+        for (name, path) in paths {
+            if path.trim().is_empty() {
+                errors.push(format!("paths.{name} is empty"));
+            }
+        }
+        if HeaderValue::from_str(&self.http.user_agent).is_err() {
+            errors.push("http.user_agent is not a valid header value".to_string());
+        }
+        if Url::parse(&self.http.manganato_referer).is_err() || HeaderValue::from_str(&self.http.manganato_referer).is_err() {
+            errors.push(format!("http.manganato_referer: {:?} is not a URL", self.http.manganato_referer));
+        }
+        let positive = [
+            ("http.connect_timeout_secs", self.http.connect_timeout_secs), ("http.read_timeout_secs", self.http.read_timeout_secs),
+            ("http.backoff_ms", self.http.backoff_ms), ("http.max_backoff_secs", self.http.max_backoff_secs),
+            ("proxy.max_image_mb", self.proxy.max_image_mb), ("proxy.timeout_secs", self.proxy.timeout_secs),
+            ("proxy.connect_timeout_secs", self.proxy.connect_timeout_secs), ("scheduler.interval_secs", self.scheduler.interval_secs),
+            ("scheduler.concurrency", self.scheduler.concurrency as u64), ("retention.budget_mb", self.retention.budget_mb),
+            ("retention.interval_secs", self.retention.interval_secs), ("downloads.concurrency", self.downloads.concurrency as u64),
+            ("page_counts.concurrency", self.page_counts.concurrency as u64),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                errors.push(format!("{name} must be greater than 0"));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(format!("invalid settings:\n  {}", errors.join("\n  "))),
+        }
+    }
+}
@@ -0,0 +1,1064 @@
+use std::{collections::HashMap, ops::RangeInclusive, sync::{Mutex, OnceLock}};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use tokio::fs;
+use crate::{
+    chapter_diff::{self, ChapterDiff},
+    config::Paths,
+    downloads::{Job, JobStatus},
+    error::{AppError, Res},
+    library::SystemTitle,
+    storage::{self, Storage},
+    timestamp,
+    user::{Account, Chapter, Progress, Title, TitleMeta, TitleStatus, User},
+};
+
+
+/// Everything the handlers need from persistent storage.
+/// Each call is atomic on its own.
+pub trait Store: Send + Sync {
+    fn find_user_id(&self, username: &str) -> Res<Option<u32>>;
+    fn load_user(&self, id: u32) -> Res<Option<User>>;
+    /// The user's credentials, without their titles and tags
+    fn load_account(&self, id: u32) -> Res<Option<Account>>;
+    fn create_user(&self, username: &str, password: &str) -> Res<User>;
+    /// Also revokes every token issued so far, returns the new token generation
+    fn set_password(&self, user_id: u32, password: &str) -> Res<u32>;
+    fn delete_user(&self, id: u32) -> Res<()>;
+
+    fn follows(&self, user_id: u32, title_id: u32) -> Res<bool>;
+    /// Follows a catalog title with fresh progress, false if it already was
+    fn follow_title(&self, user_id: u32, title_id: u32, today: &str) -> Res<bool>;
+    /// Drops the title with its progress and tag links, false if it wasn't followed
+    fn unfollow_title(&self, user_id: u32, title_id: u32) -> Res<bool>;
+    /// Replaces the user's tags, links to titles they don't follow are dropped
+    fn set_tags(&self, user_id: u32, tags: &HashMap<String, Vec<u32>>) -> Res<()>;
+
+    /// Marks chapters read or unread. Marking read moves last_chap forward to the furthest chapter marked,
+    /// unmarking leaves the reading position alone.
+    fn set_chapters_read(&self, user_id: u32, title_id: u32, chapters: RangeInclusive<u32>, read: bool, today: &str) -> Res<Progress>;
+    /// Moves the reading position without marking anything read
+    fn set_page(&self, user_id: u32, title_id: u32, chapter_id: u32, page: u32, today: &str) -> Res<Progress>;
+
+    fn find_catalog_title(&self, url: &str) -> Res<Option<u32>>;
+    fn load_catalog_title(&self, id: u32) -> Res<Option<SystemTitle>>;
+    /// Returns the new id (or the existing one if the url is already known)
+    fn add_catalog_title(&self, title: &SystemTitle) -> Res<u32>;
+    /// Saves a rescraped title. Its chapters are diffed against the stored ones by suffix, known
+    /// chapters keep what the scrape lacks (page counts, ...), and read marks and reading positions
+    /// move to where the diff put their chapters. `title.chapters` ends up as saved.
+    fn save_catalog_title(&self, title: &mut SystemTitle) -> Res<ChapterDiff>;
+    /// Deletes the title unless someone follows it, checked in the same statement. False if it was kept.
+    fn delete_catalog_title(&self, id: u32) -> Res<bool>;
+    fn follower_ids(&self, title_id: u32) -> Res<Vec<u32>>;
+    fn catalog_ids(&self) -> Res<Vec<u32>>;
+    /// Catalog titles at least one user follows
+    fn followed_title_ids(&self) -> Res<Vec<u32>>;
+
+    fn set_pinned(&self, title_id: u32, pinned: bool) -> Res<()>;
+    fn pinned_title_ids(&self) -> Res<Vec<u32>>;
+    /// (title_id, last_chap) of every user following every title
+    fn reading_positions(&self) -> Res<Vec<(u32, u32)>>;
+
+    /// (title_id, chapter index, suffix, url) of chapters whose pages were never counted
+    fn uncounted_chapters(&self) -> Res<Vec<(u32, u32, String, String)>>;
+    /// False if the chapter at `idx` no longer has `suffix`
+    fn set_page_count(&self, title_id: u32, idx: u32, suffix: &str, pages: u32) -> Res<bool>;
+    /// Suffix of the chapter at `idx`, None past the end of the list
+    fn chapter_suffix(&self, title_id: u32, idx: u32) -> Res<Option<String>>;
+
+    fn create_job(&self, user_id: u32, title_id: u32, chapters: RangeInclusive<u32>, images_total: u32, now: DateTime<Utc>) -> Res<Job>;
+    fn load_job(&self, id: i64) -> Res<Option<Job>>;
+    /// Newest first, at most 100
+    fn user_jobs(&self, user_id: u32) -> Res<Vec<Job>>;
+    /// Oldest queued job, marked running
+    fn claim_next_job(&self) -> Res<Option<Job>>;
+    /// Status, counters and errors
+    fn save_job(&self, job: &Job) -> Res<()>;
+    /// Marks a queued or running job cancelled, false if it already finished.
+    /// A worker that just claimed the job finds the mark before starting.
+    fn cancel_job(&self, id: i64, now: DateTime<Utc>) -> Res<bool>;
+    /// Jobs a stopped process was running go back in the queue, returns how many
+    fn requeue_running_jobs(&self) -> Res<usize>;
+    /// Queued and running jobs, oldest first
+    fn unfinished_jobs(&self) -> Res<Vec<Job>>;
+}
+
+static STORE: OnceLock<Box<dyn Store>> = OnceLock::new();
+
+pub fn store() -> &'static dyn Store {
+    STORE.get().expect("db::init not called").as_ref()
+}
+
+/// Opens the database and imports the old JSON files on first run
+pub async fn init(paths: &Paths, storage: &Storage) -> Res<()> {
+    let (store, from_version) = SqliteStore::open(&paths.database)?;
+    if from_version < 2 {
+        storage.retire_covers().await?;
+    }
+    import_json(&store, &paths.legacy_db, &paths.legacy_users).await?;
+    STORE.set(Box::new(store)).ok();
+    Ok(())
+}
+
+// Applied in order, PRAGMA user_version records how many already ran
+const MIGRATIONS: &[&str] = &[
+    // 1: per-user titles
+    "CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        password TEXT NOT NULL
+    );
+    CREATE TABLE titles (
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        url TEXT NOT NULL,
+        chap_prefix TEXT NOT NULL,
+        last_updated TEXT NOT NULL,
+        last_scanned TEXT NOT NULL,
+        PRIMARY KEY (user_id, id)
+    );
+    CREATE TABLE chapters (
+        user_id INTEGER NOT NULL,
+        title_id INTEGER NOT NULL,
+        idx INTEGER NOT NULL,
+        t TEXT NOT NULL,
+        s TEXT NOT NULL,
+        i INTEGER NOT NULL,
+        PRIMARY KEY (user_id, title_id, idx),
+        FOREIGN KEY (user_id, title_id) REFERENCES titles(user_id, id) ON DELETE CASCADE
+    );
+    CREATE TABLE progress (
+        user_id INTEGER NOT NULL,
+        title_id INTEGER NOT NULL,
+        last_chap INTEGER NOT NULL,
+        last_read TEXT NOT NULL,
+        PRIMARY KEY (user_id, title_id),
+        FOREIGN KEY (user_id, title_id) REFERENCES titles(user_id, id) ON DELETE CASCADE
+    );
+    CREATE TABLE tags (
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        PRIMARY KEY (user_id, name)
+    );
+    CREATE TABLE title_tags (
+        user_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        title_id INTEGER NOT NULL,
+        PRIMARY KEY (user_id, name, title_id),
+        FOREIGN KEY (user_id, name) REFERENCES tags(user_id, name) ON DELETE CASCADE,
+        FOREIGN KEY (user_id, title_id) REFERENCES titles(user_id, id) ON DELETE CASCADE
+    );",
+    // 2: shared catalog, users keep references to it
+    "CREATE TABLE catalog_titles (
+        id INTEGER PRIMARY KEY,
+        url TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        chap_prefix TEXT NOT NULL,
+        last_updated TEXT NOT NULL,
+        last_scanned TEXT NOT NULL
+    );
+    CREATE TABLE catalog_chapters (
+        title_id INTEGER NOT NULL REFERENCES catalog_titles(id) ON DELETE CASCADE,
+        idx INTEGER NOT NULL,
+        t TEXT NOT NULL,
+        s TEXT NOT NULL,
+        i INTEGER NOT NULL,
+        PRIMARY KEY (title_id, idx)
+    );
+
+    -- per-user copies of the same url collapse into one, keeping the most complete
+    CREATE TEMP TABLE keep AS
+        SELECT t.url, t.user_id, t.id FROM titles t
+        WHERE t.rowid = (
+            SELECT t2.rowid FROM titles t2 WHERE t2.url = t.url
+            ORDER BY (SELECT COUNT(*) FROM chapters c WHERE c.user_id = t2.user_id AND c.title_id = t2.id) DESC, t2.last_scanned DESC
+            LIMIT 1
+        );
+    INSERT INTO catalog_titles (url, name, chap_prefix, last_updated, last_scanned)
+        SELECT t.url, t.name, t.chap_prefix, t.last_updated, t.last_scanned
+        FROM keep k JOIN titles t ON t.user_id = k.user_id AND t.id = k.id;
+    INSERT INTO catalog_chapters (title_id, idx, t, s, i)
+        SELECT ct.id, c.idx, c.t, c.s, c.i
+        FROM keep k
+        JOIN catalog_titles ct ON ct.url = k.url
+        JOIN chapters c ON c.user_id = k.user_id AND c.title_id = k.id;
+
+    -- per-user title id -> catalog id
+    CREATE TEMP TABLE title_map AS
+        SELECT t.user_id, t.id AS old_id, ct.id AS new_id, t.rowid AS position
+        FROM titles t JOIN catalog_titles ct ON ct.url = t.url;
+    CREATE TEMP TABLE old_progress AS
+        SELECT m.user_id, m.new_id AS title_id, p.last_chap, p.last_read, m.position
+        FROM progress p JOIN title_map m ON m.user_id = p.user_id AND m.old_id = p.title_id;
+    CREATE TEMP TABLE old_title_tags AS
+        SELECT tt.user_id, tt.name, m.new_id AS title_id, tt.rowid AS position
+        FROM title_tags tt JOIN title_map m ON m.user_id = tt.user_id AND m.old_id = tt.title_id;
+
+    DROP TABLE title_tags;
+    DROP TABLE progress;
+    DROP TABLE chapters;
+    DROP TABLE titles;
+
+    CREATE TABLE library (
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        title_id INTEGER NOT NULL REFERENCES catalog_titles(id),
+        PRIMARY KEY (user_id, title_id)
+    );
+    CREATE TABLE progress (
+        user_id INTEGER NOT NULL,
+        title_id INTEGER NOT NULL,
+        last_chap INTEGER NOT NULL,
+        last_read TEXT NOT NULL,
+        PRIMARY KEY (user_id, title_id),
+        FOREIGN KEY (user_id, title_id) REFERENCES library(user_id, title_id) ON DELETE CASCADE
+    );
+    CREATE TABLE title_tags (
+        user_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        title_id INTEGER NOT NULL,
+        PRIMARY KEY (user_id, name, title_id),
+        FOREIGN KEY (user_id, name) REFERENCES tags(user_id, name) ON DELETE CASCADE,
+        FOREIGN KEY (user_id, title_id) REFERENCES library(user_id, title_id) ON DELETE CASCADE
+    );
+
+    INSERT OR IGNORE INTO library (user_id, title_id)
+        SELECT user_id, new_id FROM title_map ORDER BY position;
+    INSERT OR IGNORE INTO progress (user_id, title_id, last_chap, last_read)
+        SELECT user_id, title_id, last_chap, last_read FROM old_progress ORDER BY position;
+    INSERT OR IGNORE INTO title_tags (user_id, name, title_id)
+        SELECT user_id, name, title_id FROM old_title_tags ORDER BY position;
+
+    DROP TABLE temp.keep;
+    DROP TABLE temp.title_map;
+    DROP TABLE temp.old_progress;
+    DROP TABLE temp.old_title_tags;",
+    // 3: per-chapter read marks and page position
+    "ALTER TABLE progress ADD COLUMN last_page INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE chapter_reads (
+        user_id INTEGER NOT NULL,
+        title_id INTEGER NOT NULL,
+        idx INTEGER NOT NULL,
+        PRIMARY KEY (user_id, title_id, idx),
+        FOREIGN KEY (user_id, title_id) REFERENCES library(user_id, title_id) ON DELETE CASCADE
+    );
+
+    -- last_chap is the chapter being read, the ones before it were read
+    WITH RECURSIVE reads(user_id, title_id, idx, last_chap) AS (
+        SELECT user_id, title_id, 0, last_chap FROM progress WHERE last_chap > 0
+        UNION ALL
+        SELECT user_id, title_id, idx + 1, last_chap FROM reads WHERE idx + 1 < last_chap
+    )
+    INSERT INTO chapter_reads (user_id, title_id, idx) SELECT user_id, title_id, idx FROM reads;",
+    // 4: titles kept on disk by the retention policy
+    "ALTER TABLE catalog_titles ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;",
+    // 5: download queue
+    "CREATE TABLE download_jobs (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        title_id INTEGER NOT NULL REFERENCES catalog_titles(id) ON DELETE CASCADE,
+        first_chap INTEGER NOT NULL,
+        last_chap INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        chapters_done INTEGER NOT NULL DEFAULT 0,
+        images_done INTEGER NOT NULL DEFAULT 0,
+        images_total INTEGER NOT NULL,
+        bytes INTEGER NOT NULL DEFAULT 0,
+        errors TEXT NOT NULL DEFAULT '[]', -- JSON list
+        created_at TEXT NOT NULL,
+        finished_at TEXT
+    );
+    CREATE INDEX download_jobs_status ON download_jobs(status, id);
+    CREATE INDEX download_jobs_user ON download_jobs(user_id, id);",
+    // 6: chapter metadata, number and volume are filled from the text by parse_chapter_labels
+    "ALTER TABLE catalog_chapters ADD COLUMN number REAL;
+    ALTER TABLE catalog_chapters ADD COLUMN volume TEXT;
+    ALTER TABLE catalog_chapters ADD COLUMN released TEXT;
+    ALTER TABLE catalog_chapters ADD COLUMN group_name TEXT;
+    ALTER TABLE catalog_chapters ADD COLUMN language TEXT;",
+    // 7: title metadata, lists as JSON, filled in by the next update
+    "ALTER TABLE catalog_titles ADD COLUMN authors TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE catalog_titles ADD COLUMN artists TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE catalog_titles ADD COLUMN genres TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE catalog_titles ADD COLUMN status TEXT;
+    ALTER TABLE catalog_titles ADD COLUMN synopsis TEXT;
+    ALTER TABLE catalog_titles ADD COLUMN alt_titles TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE catalog_titles ADD COLUMN rating REAL;",
+    // 8: ids of deleted users are never handed out again, tokens carry the user's generation
+    "CREATE TABLE users_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL UNIQUE,
+        password TEXT NOT NULL,
+        token_generation INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT INTO users_new (id, username, password) SELECT id, username, password FROM users;
+    DROP TABLE users;
+    ALTER TABLE users_new RENAME TO users;",
+    // 9: set in the same transaction as the import of db.json
+    "CREATE TABLE legacy_import (imported_at TEXT NOT NULL);",
+    // 10: catalog ids aren't handed out again either, clients cache pages by title id for good
+    "CREATE TABLE catalog_titles_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        url TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        chap_prefix TEXT NOT NULL,
+        last_updated TEXT NOT NULL,
+        last_scanned TEXT NOT NULL,
+        pinned INTEGER NOT NULL DEFAULT 0,
+        authors TEXT NOT NULL DEFAULT '[]',
+        artists TEXT NOT NULL DEFAULT '[]',
+        genres TEXT NOT NULL DEFAULT '[]',
+        status TEXT,
+        synopsis TEXT,
+        alt_titles TEXT NOT NULL DEFAULT '[]',
+        rating REAL
+    );
+    INSERT INTO catalog_titles_new
+        SELECT id, url, name, chap_prefix, last_updated, last_scanned, pinned, authors, artists, genres, status, synopsis, alt_titles, rating
+        FROM catalog_titles;
+    DROP TABLE catalog_titles;
+    ALTER TABLE catalog_titles_new RENAME TO catalog_titles;",
+    // 11: a page count of 0 is a count too
+    "ALTER TABLE catalog_chapters ADD COLUMN counted INTEGER NOT NULL DEFAULT 0;
+    UPDATE catalog_chapters SET counted = 1 WHERE i > 0;",
+];
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Also returns the schema version the file had before migrating
+    pub fn open(path: &str) -> Res<(SqliteStore, u32)> {
+        let mut conn = Connection::open(path)?;
+        // off while migrating, so rebuilding a table doesn't cascade into its children
+        conn.execute_batch("PRAGMA foreign_keys = OFF; PRAGMA journal_mode = WAL;")?;
+
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            if i + 1 == 6 {
+                Self::parse_chapter_labels(&tx)?;
+            }
+            if tx.prepare("PRAGMA foreign_key_check")?.exists([])? {
+                return Err(AppError::Storage(format!("migration {} broke a foreign key", i + 1)));
+            }
+            tx.pragma_update(None, "user_version", i as u32 + 1)?;
+            tx.commit()?;
+            println!("Applied database migration {}", i + 1);
+        }
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+
+        Ok((SqliteStore { conn: Mutex::new(conn) }, version))
+    }
+
+    // chapters stored before migration 6 only have their text
+    fn parse_chapter_labels(conn: &Connection) -> Res<()> {
+        let chapters: Vec<(u32, u32, String)> = conn.prepare("SELECT title_id, idx, t FROM catalog_chapters")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        let mut update = conn.prepare("UPDATE catalog_chapters SET number = ?3, volume = ?4 WHERE title_id = ?1 AND idx = ?2")?;
+        for (title_id, idx, t) in chapters {
+            let (number, volume) = Chapter::parse_label(&t);
+            update.execute(params![title_id, idx, number, volume])?;
+        }
+        Ok(())
+    }
+
+    fn load_titles(conn: &Connection, user_id: u32) -> Res<Vec<Title>> {
+        let mut titles = conn.prepare(
+            "SELECT t.id, t.name, t.url, t.chap_prefix, p.last_chap, p.last_page, t.last_updated, p.last_read, t.last_scanned
+             FROM library l
+             JOIN catalog_titles t ON t.id = l.title_id
+             JOIN progress p ON p.user_id = l.user_id AND p.title_id = l.title_id
+             WHERE l.user_id = ?1 ORDER BY l.rowid",
+        )?
+        .query_map([user_id], |row| Ok(Title {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            url: row.get(2)?,
+            chap_prefix: row.get(3)?,
+            last_chap: row.get(4)?,
+            last_page: row.get(5)?,
+            read: Vec::new(),
+            last_updated: row.get(6)?,
+            last_read: row.get(7)?,
+            last_scanned: row.get(8)?,
+            tags: Vec::new(),
+            meta: TitleMeta::default(),
+            chapters: Vec::new(),
+        }))?
+        .collect::<Result<Vec<Title>, _>>()?;
+
+        let mut tags = conn.prepare("SELECT name FROM title_tags WHERE user_id = ?1 AND title_id = ?2 ORDER BY rowid")?;
+        for title in titles.iter_mut() {
+            title.meta = Self::load_meta(conn, title.id)?;
+            title.chapters = Self::load_chapters(conn, title.id)?;
+            title.read = Self::load_reads(conn, user_id, title.id)?;
+            title.tags = tags
+                .query_map([user_id, title.id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(titles)
+    }
+
+    fn load_account(conn: &Connection, id: u32) -> Res<Option<Account>> {
+        Ok(conn.query_row(
+            "SELECT username, password, token_generation FROM users WHERE id = ?1",
+            [id],
+            |row| Ok(Account { id, username: row.get(0)?, password: row.get(1)?, token_generation: row.get(2)? }),
+        ).optional()?)
+    }
+
+    fn load_chapters(conn: &Connection, title_id: u32) -> Res<Vec<Chapter>> {
+        Ok(conn.prepare_cached(
+            "SELECT t, s, i, counted, number, volume, released, group_name, language FROM catalog_chapters WHERE title_id = ?1 ORDER BY idx",
+        )?
+            .query_map([title_id], |row| Ok(Chapter {
+                t: row.get(0)?,
+                s: row.get(1)?,
+                i: row.get(2)?,
+                counted: row.get(3)?,
+                number: row.get(4)?,
+                volume: row.get(5)?,
+                released: row.get(6)?,
+                group: row.get(7)?,
+                language: row.get(8)?,
+            }))?
+            .collect::<Result<_, _>>()?)
+    }
+
+    fn load_meta(conn: &Connection, title_id: u32) -> Res<TitleMeta> {
+        Ok(conn.prepare_cached(
+            "SELECT authors, artists, genres, status, synopsis, alt_titles, rating FROM catalog_titles WHERE id = ?1",
+        )?
+        .query_row([title_id], |row| {
+            let list = |index| row.get::<_, String>(index).map(|json| serde_json::from_str(&json).unwrap_or_default());
+            Ok(TitleMeta {
+                authors: list(0)?,
+                artists: list(1)?,
+                genres: list(2)?,
+                status: row.get::<_, Option<String>>(3)?.as_deref().and_then(TitleStatus::parse),
+                synopsis: row.get(4)?,
+                alt_titles: list(5)?,
+                rating: row.get(6)?,
+            })
+        })?)
+    }
+
+    fn save_meta(conn: &Connection, title_id: u32, meta: &TitleMeta) -> Res<()> {
+        conn.prepare_cached(
+            "UPDATE catalog_titles SET authors = ?2, artists = ?3, genres = ?4, status = ?5, synopsis = ?6, alt_titles = ?7, rating = ?8 WHERE id = ?1",
+        )?
+        .execute(params![
+            title_id,
+            serde_json::to_string(&meta.authors)?,
+            serde_json::to_string(&meta.artists)?,
+            serde_json::to_string(&meta.genres)?,
+            meta.status.map(|status| status.as_str()),
+            meta.synopsis,
+            serde_json::to_string(&meta.alt_titles)?,
+            meta.rating,
+        ])?;
+        Ok(())
+    }
+
+    fn load_reads(conn: &Connection, user_id: u32, title_id: u32) -> Res<Vec<u32>> {
+        Ok(conn.prepare_cached("SELECT idx FROM chapter_reads WHERE user_id = ?1 AND title_id = ?2 ORDER BY idx")?
+            .query_map([user_id, title_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?)
+    }
+
+    fn load_progress(conn: &Connection, user_id: u32, title_id: u32) -> Res<Progress> {
+        let (last_chap, last_page, last_read) = conn.query_row(
+            "SELECT last_chap, last_page, last_read FROM progress WHERE user_id = ?1 AND title_id = ?2",
+            [user_id, title_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).optional()?.ok_or_else(|| AppError::not_found("Title Does Not Exist"))?;
+
+        Ok(Progress { title_id, last_chap, last_page, last_read, read: Self::load_reads(conn, user_id, title_id)? })
+    }
+
+    // progress endpoints only accept chapters the catalog knows about
+    fn check_chapter(conn: &Connection, user_id: u32, title_id: u32, chapter_id: u32) -> Res<()> {
+        Self::load_progress(conn, user_id, title_id)?;
+        let chapters: u32 = conn.query_row("SELECT COUNT(*) FROM catalog_chapters WHERE title_id = ?1", [title_id], |row| row.get(0))?;
+        if chapter_id >= chapters {
+            return Err(AppError::BadRequest(format!("Title {title_id} has no chapter {chapter_id}")));
+        }
+        Ok(())
+    }
+
+    fn insert_chapters(conn: &Connection, title_id: u32, chapters: &[Chapter]) -> Res<()> {
+        let mut insert_chapter = conn.prepare_cached(
+            "INSERT INTO catalog_chapters (title_id, idx, t, s, i, counted, number, volume, released, group_name, language)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?;
+        for (idx, chapter) in (0u32..).zip(chapters) {
+            insert_chapter.execute(params![
+                title_id, idx, chapter.t, chapter.s, chapter.i, chapter.counted,
+                chapter.number, chapter.volume, chapter.released, chapter.group, chapter.language,
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn insert_catalog_title(conn: &Connection, title: &SystemTitle) -> Res<u32> {
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO catalog_titles (url, name, chap_prefix, last_updated, last_scanned) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![title.url, title.name, title.chap_prefix, title.last_updated, title.last_scanned],
+        )?;
+        let id: u32 = conn.query_row("SELECT id FROM catalog_titles WHERE url = ?1", [&title.url], |row| row.get(0))?;
+        if inserted > 0 {
+            Self::save_meta(conn, id, &title.meta)?;
+            Self::insert_chapters(conn, id, &title.chapters)?;
+        }
+        Ok(id)
+    }
+
+    fn load_job(conn: &Connection, condition: &str, params: impl rusqlite::Params) -> Res<Vec<Job>> {
+        let mut statement = conn.prepare(&format!(
+            "SELECT id, user_id, title_id, first_chap, last_chap, status, chapters_done, images_done, images_total, bytes, errors, created_at, finished_at
+             FROM download_jobs WHERE {condition}"
+        ))?;
+        let jobs = statement.query_map(params, |row| {
+            let status: String = row.get(5)?;
+            let errors: String = row.get(10)?;
+            let finished_at: Option<String> = row.get(12)?;
+            Ok(Job {
+                id: row.get(0)?,
+                user_id: row.get(1)?,
+                title_id: row.get(2)?,
+                from: row.get(3)?,
+                to: row.get(4)?,
+                status: JobStatus::parse(&status).unwrap_or(JobStatus::Failed),
+                chapters_done: row.get(6)?,
+                images_done: row.get(7)?,
+                images_total: row.get(8)?,
+                bytes: row.get::<_, i64>(9)? as u64,
+                errors: serde_json::from_str(&errors).unwrap_or_default(),
+                created_at: parse_time(&row.get::<_, String>(11)?),
+                finished_at: finished_at.as_deref().map(parse_time),
+            })
+        })?;
+        Ok(jobs.collect::<Result<_, _>>()?)
+    }
+
+    // read marks of removed chapters are dropped, a position on one moves back to the closest chapter left
+    fn remap_progress(conn: &Connection, title_id: u32, diff: &ChapterDiff) -> Res<()> {
+        let reads: Vec<(u32, u32)> = conn.prepare("SELECT user_id, idx FROM chapter_reads WHERE title_id = ?1")?
+            .query_map([title_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        conn.execute("DELETE FROM chapter_reads WHERE title_id = ?1", [title_id])?;
+        let mut insert_read = conn.prepare("INSERT OR IGNORE INTO chapter_reads (user_id, title_id, idx) VALUES (?1, ?2, ?3)")?;
+        for (user_id, idx) in reads {
+            if let Some(Some(new)) = diff.remap.get(idx as usize) {
+                insert_read.execute(params![user_id, title_id, new])?;
+            }
+        }
+
+        let positions: Vec<(u32, u32)> = conn.prepare("SELECT user_id, last_chap FROM progress WHERE title_id = ?1")?
+            .query_map([title_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let mut update_position = conn.prepare(
+            "UPDATE progress SET last_chap = ?3, last_page = CASE WHEN ?4 THEN last_page ELSE 0 END WHERE user_id = ?1 AND title_id = ?2",
+        )?;
+        for (user_id, last_chap) in positions {
+            let kept = matches!(diff.remap.get(last_chap as usize), Some(Some(_)));
+            update_position.execute(params![user_id, title_id, diff.position(last_chap), kept])?;
+        }
+        Ok(())
+    }
+
+    fn load_tags(conn: &Connection, user_id: u32) -> Res<HashMap<String, Vec<u32>>> {
+        let mut tags: HashMap<String, Vec<u32>> = HashMap::new();
+        let mut names = conn.prepare("SELECT name FROM tags WHERE user_id = ?1")?;
+        for name in names.query_map([user_id], |row| row.get::<_, String>(0))? {
+            tags.insert(name?, Vec::new());
+        }
+        let mut links = conn.prepare("SELECT name, title_id FROM title_tags WHERE user_id = ?1 ORDER BY rowid")?;
+        for link in links.query_map([user_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?)))? {
+            let (name, title_id) = link?;
+            tags.entry(name).or_default().push(title_id);
+        }
+        Ok(tags)
+    }
+
+    // both User.tags and Title.tags describe the same links, take the union
+    fn insert_user_rows(conn: &Connection, user: &User) -> Res<()> {
+        let mut follow = conn.prepare("INSERT OR IGNORE INTO library (user_id, title_id) VALUES (?1, ?2)")?;
+        let mut insert_progress = conn.prepare("INSERT OR IGNORE INTO progress (user_id, title_id, last_chap, last_page, last_read) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+        let mut insert_read = conn.prepare("INSERT OR IGNORE INTO chapter_reads (user_id, title_id, idx) VALUES (?1, ?2, ?3)")?;
+        let mut insert_tag = conn.prepare("INSERT OR IGNORE INTO tags (user_id, name) VALUES (?1, ?2)")?;
+        let mut link_tag = conn.prepare("INSERT OR IGNORE INTO title_tags (user_id, name, title_id) VALUES (?1, ?2, ?3)")?;
+
+        for title in &user.titles {
+            follow.execute(params![user.id, title.id])?;
+            insert_progress.execute(params![user.id, title.id, title.last_chap, title.last_page, title.last_read])?;
+            for idx in &title.read {
+                insert_read.execute(params![user.id, title.id, idx])?;
+            }
+            for tag in &title.tags {
+                insert_tag.execute(params![user.id, tag])?;
+                link_tag.execute(params![user.id, tag, title.id])?;
+            }
+        }
+
+        let title_ids: Vec<u32> = user.titles.iter().map(|title| title.id).collect();
+        for (tag, ids) in &user.tags {
+            insert_tag.execute(params![user.id, tag])?;
+            for id in ids.iter().filter(|id| title_ids.contains(id)) {
+                link_tag.execute(params![user.id, tag, id])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Store for SqliteStore {
+    fn find_user_id(&self, username: &str) -> Res<Option<u32>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row("SELECT id FROM users WHERE username = ?1", [username], |row| row.get(0)).optional()?)
+    }
+
+    fn load_user(&self, id: u32) -> Res<Option<User>> {
+        let conn = self.conn.lock().unwrap();
+        let Some(Account { id, username, password, .. }) = Self::load_account(&conn, id)? else { return Ok(None); };
+
+        Ok(Some(User {
+            id,
+            username,
+            password,
+            titles: Self::load_titles(&conn, id)?,
+            tags: Self::load_tags(&conn, id)?,
+        }))
+    }
+
+    fn load_account(&self, id: u32) -> Res<Option<Account>> {
+        let conn = self.conn.lock().unwrap();
+        Self::load_account(&conn, id)
+    }
+
+    fn create_user(&self, username: &str, password: &str) -> Res<User> {
+        let conn = self.conn.lock().unwrap();
+        let inserted = conn.execute("INSERT OR IGNORE INTO users (username, password) VALUES (?1, ?2)", [username, password])?;
+        if inserted == 0 {
+            return Err(AppError::BadRequest(format!("Username already exists: {username}")));
+        }
+        Ok(User {
+            id: conn.last_insert_rowid() as u32,
+            username: username.to_string(),
+            password: password.to_string(),
+            titles: Vec::new(),
+            tags: HashMap::new(),
+        })
+    }
+
+    fn set_password(&self, user_id: u32, password: &str) -> Res<u32> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "UPDATE users SET password = ?2, token_generation = token_generation + 1 WHERE id = ?1 RETURNING token_generation",
+            params![user_id, password],
+            |row| row.get(0),
+        ).optional()?.ok_or_else(|| AppError::not_found("User Does Not Exist"))
+    }
+
+    fn delete_user(&self, id: u32) -> Res<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM users WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn follows(&self, user_id: u32, title_id: u32) -> Res<bool> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM library WHERE user_id = ?1 AND title_id = ?2)",
+            [user_id, title_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn follow_title(&self, user_id: u32, title_id: u32, today: &str) -> Res<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let followed = tx.execute("INSERT OR IGNORE INTO library (user_id, title_id) VALUES (?1, ?2)", params![user_id, title_id])? > 0;
+        if followed {
+            tx.execute(
+                "INSERT INTO progress (user_id, title_id, last_chap, last_page, last_read) VALUES (?1, ?2, 0, 0, ?3)",
+                params![user_id, title_id, today],
+            )?;
+        }
+        tx.commit()?;
+        Ok(followed)
+    }
+
+    fn unfollow_title(&self, user_id: u32, title_id: u32) -> Res<bool> {
+        let conn = self.conn.lock().unwrap();
+        // cascades to progress, read marks and tag links
+        Ok(conn.execute("DELETE FROM library WHERE user_id = ?1 AND title_id = ?2", params![user_id, title_id])? > 0)
+    }
+
+    fn set_tags(&self, user_id: u32, tags: &HashMap<String, Vec<u32>>) -> Res<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        // cascades to the links
+        tx.execute("DELETE FROM tags WHERE user_id = ?1", [user_id])?;
+        let mut insert_tag = tx.prepare("INSERT INTO tags (user_id, name) VALUES (?1, ?2)")?;
+        let mut link_tag = tx.prepare(
+            "INSERT OR IGNORE INTO title_tags (user_id, name, title_id)
+             SELECT ?1, ?2, ?3 WHERE EXISTS (SELECT 1 FROM library WHERE user_id = ?1 AND title_id = ?3)",
+        )?;
+        for (tag, ids) in tags {
+            insert_tag.execute(params![user_id, tag])?;
+            for id in ids {
+                link_tag.execute(params![user_id, tag, id])?;
+            }
+        }
+        drop((insert_tag, link_tag));
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn set_chapters_read(&self, user_id: u32, title_id: u32, chapters: RangeInclusive<u32>, read: bool, today: &str) -> Res<Progress> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        Self::check_chapter(&tx, user_id, title_id, *chapters.end())?;
+
+        let statement = if read {
+            "INSERT OR IGNORE INTO chapter_reads (user_id, title_id, idx) VALUES (?1, ?2, ?3)"
+        } else {
+            "DELETE FROM chapter_reads WHERE user_id = ?1 AND title_id = ?2 AND idx = ?3"
+        };
+        let furthest = *chapters.end();
+        let mut mark = tx.prepare(statement)?;
+        for idx in chapters {
+            mark.execute(params![user_id, title_id, idx])?;
+        }
+        drop(mark);
+
+        if read {
+            tx.execute(
+                "UPDATE progress SET
+                    last_page = CASE WHEN ?3 > last_chap THEN 0 ELSE last_page END,
+                    last_chap = MAX(last_chap, ?3),
+                    last_read = ?4
+                 WHERE user_id = ?1 AND title_id = ?2",
+                params![user_id, title_id, furthest, today],
+            )?;
+        } else {
+            tx.execute("UPDATE progress SET last_read = ?3 WHERE user_id = ?1 AND title_id = ?2", params![user_id, title_id, today])?;
+        }
+        let progress = Self::load_progress(&tx, user_id, title_id)?;
+        tx.commit()?;
+        Ok(progress)
+    }
+
+    fn set_page(&self, user_id: u32, title_id: u32, chapter_id: u32, page: u32, today: &str) -> Res<Progress> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        Self::check_chapter(&tx, user_id, title_id, chapter_id)?;
+        tx.execute(
+            "UPDATE progress SET last_chap = ?3, last_page = ?4, last_read = ?5 WHERE user_id = ?1 AND title_id = ?2",
+            params![user_id, title_id, chapter_id, page, today],
+        )?;
+        let progress = Self::load_progress(&tx, user_id, title_id)?;
+        tx.commit()?;
+        Ok(progress)
+    }
+
+    fn find_catalog_title(&self, url: &str) -> Res<Option<u32>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row("SELECT id FROM catalog_titles WHERE url = ?1", [url], |row| row.get(0)).optional()?)
+    }
+
+    fn load_catalog_title(&self, id: u32) -> Res<Option<SystemTitle>> {
+        let conn = self.conn.lock().unwrap();
+        let Some(mut title) = conn.query_row(
+            "SELECT name, url, chap_prefix, last_updated, last_scanned FROM catalog_titles WHERE id = ?1",
+            [id],
+            |row| Ok(SystemTitle {
+                id,
+                name: row.get(0)?,
+                url: row.get(1)?,
+                chap_prefix: row.get(2)?,
+                last_updated: row.get(3)?,
+                last_scanned: row.get(4)?,
+                meta: TitleMeta::default(),
+                chapters: Vec::new(),
+            }),
+        ).optional()? else { return Ok(None); };
+
+        title.meta = Self::load_meta(&conn, id)?;
+        title.chapters = Self::load_chapters(&conn, id)?;
+        Ok(Some(title))
+    }
+
+    fn add_catalog_title(&self, title: &SystemTitle) -> Res<u32> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let id = Self::insert_catalog_title(&tx, title)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    fn save_catalog_title(&self, title: &mut SystemTitle) -> Res<ChapterDiff> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let stored = Self::load_chapters(&tx, title.id)?;
+        let diff = chapter_diff::diff(&stored, &title.chapters);
+        chapter_diff::carry_over(&stored, &mut title.chapters, &diff);
+
+        tx.execute(
+            "UPDATE catalog_titles SET name = ?2, chap_prefix = ?3, last_updated = ?4, last_scanned = ?5 WHERE id = ?1",
+            params![title.id, title.name, title.chap_prefix, title.last_updated, title.last_scanned],
+        )?;
+        Self::save_meta(&tx, title.id, &title.meta)?;
+        tx.execute("DELETE FROM catalog_chapters WHERE title_id = ?1", [title.id])?;
+        Self::insert_chapters(&tx, title.id, &title.chapters)?;
+        if diff.moves_indexes() {
+            Self::remap_progress(&tx, title.id, &diff)?;
+        }
+        tx.commit()?;
+        Ok(diff)
+    }
+
+    fn delete_catalog_title(&self, id: u32) -> Res<bool> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn.execute(
+            "DELETE FROM catalog_titles WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM library WHERE title_id = ?1)",
+            [id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn follower_ids(&self, title_id: u32) -> Res<Vec<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT user_id FROM library WHERE title_id = ?1")?;
+        let ids = statement.query_map([title_id], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    fn catalog_ids(&self) -> Res<Vec<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT id FROM catalog_titles")?;
+        let ids = statement.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    fn followed_title_ids(&self) -> Res<Vec<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT DISTINCT title_id FROM library ORDER BY title_id")?;
+        let ids = statement.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    fn set_pinned(&self, title_id: u32, pinned: bool) -> Res<()> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute("UPDATE catalog_titles SET pinned = ?2 WHERE id = ?1", params![title_id, pinned])?;
+        if updated == 0 {
+            return Err(AppError::not_found("Title Does Not Exist"));
+        }
+        Ok(())
+    }
+
+    fn pinned_title_ids(&self) -> Res<Vec<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT id FROM catalog_titles WHERE pinned ORDER BY id")?;
+        let ids = statement.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    fn reading_positions(&self) -> Res<Vec<(u32, u32)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT title_id, last_chap FROM progress")?;
+        let positions = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+        Ok(positions)
+    }
+
+    fn uncounted_chapters(&self) -> Res<Vec<(u32, u32, String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT c.title_id, c.idx, c.s, t.chap_prefix || c.s FROM catalog_chapters c
+             JOIN catalog_titles t ON t.id = c.title_id
+             WHERE c.counted = 0 ORDER BY c.title_id, c.idx",
+        )?;
+        let chapters = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?.collect::<Result<_, _>>()?;
+        Ok(chapters)
+    }
+
+    fn set_page_count(&self, title_id: u32, idx: u32, suffix: &str, pages: u32) -> Res<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE catalog_chapters SET i = ?4, counted = 1 WHERE title_id = ?1 AND idx = ?2 AND s = ?3",
+            params![title_id, idx, suffix, pages],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn chapter_suffix(&self, title_id: u32, idx: u32) -> Res<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row(
+            "SELECT s FROM catalog_chapters WHERE title_id = ?1 AND idx = ?2",
+            [title_id, idx],
+            |row| row.get(0),
+        ).optional()?)
+    }
+
+    fn create_job(&self, user_id: u32, title_id: u32, chapters: RangeInclusive<u32>, images_total: u32, now: DateTime<Utc>) -> Res<Job> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO download_jobs (user_id, title_id, first_chap, last_chap, status, images_total, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![user_id, title_id, chapters.start(), chapters.end(), JobStatus::Queued.as_str(), images_total, now.to_rfc3339()],
+        )?;
+        let id = conn.last_insert_rowid();
+        Self::load_job(&conn, "id = ?1", [id])?.pop().ok_or_else(|| AppError::Storage("job vanished".to_string()))
+    }
+
+    fn load_job(&self, id: i64) -> Res<Option<Job>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(Self::load_job(&conn, "id = ?1", [id])?.pop())
+    }
+
+    fn user_jobs(&self, user_id: u32) -> Res<Vec<Job>> {
+        let conn = self.conn.lock().unwrap();
+        Self::load_job(&conn, "user_id = ?1 ORDER BY id DESC LIMIT 100", [user_id])
+    }
+
+    fn claim_next_job(&self) -> Res<Option<Job>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some(mut job) = Self::load_job(&tx, "status = ?1 ORDER BY id LIMIT 1", [JobStatus::Queued.as_str()])?.pop() else {
+            return Ok(None);
+        };
+        tx.execute("UPDATE download_jobs SET status = ?2 WHERE id = ?1", params![job.id, JobStatus::Running.as_str()])?;
+        tx.commit()?;
+        job.status = JobStatus::Running;
+        Ok(Some(job))
+    }
+
+    fn save_job(&self, job: &Job) -> Res<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE download_jobs SET status = ?2, chapters_done = ?3, images_done = ?4, images_total = ?5, bytes = ?6, errors = ?7, finished_at = ?8 WHERE id = ?1",
+            params![job.id, job.status.as_str(), job.chapters_done, job.images_done, job.images_total, job.bytes as i64, serde_json::to_string(&job.errors)?, job.finished_at.map(|time| time.to_rfc3339())],
+        )?;
+        Ok(())
+    }
+
+    fn cancel_job(&self, id: i64, now: DateTime<Utc>) -> Res<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE download_jobs SET status = ?2, finished_at = ?3 WHERE id = ?1 AND status IN (?4, ?5)",
+            params![id, JobStatus::Cancelled.as_str(), now.to_rfc3339(), JobStatus::Queued.as_str(), JobStatus::Running.as_str()],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn requeue_running_jobs(&self) -> Res<usize> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("UPDATE download_jobs SET status = ?1 WHERE status = ?2", [JobStatus::Queued.as_str(), JobStatus::Running.as_str()])?)
+    }
+
+    fn unfinished_jobs(&self) -> Res<Vec<Job>> {
+        let conn = self.conn.lock().unwrap();
+        Self::load_job(&conn, "status IN (?1, ?2) ORDER BY id", [JobStatus::Queued.as_str(), JobStatus::Running.as_str()])
+    }
+}
+
+// job timestamps are stored as RFC 3339
+fn parse_time(time: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Utc)).unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+struct LegacyDB {
+    users: HashMap<String, u32>,
+}
+
+/// One-time import of db.json and users/{id}.json (./public by default).
+/// Keeps the user ids and moves titles into the catalog. Every user goes in one transaction that
+/// also records the import, unreadable files are skipped. db.json is renamed afterwards.
+async fn import_json(store: &SqliteStore, legacy_db: &str, legacy_users: &str) -> Res<()> {
+    let Ok(json_str) = storage::open_json(legacy_db).await else { return Ok(()); };
+    let imported: bool = store.conn.lock().unwrap().query_row("SELECT EXISTS (SELECT 1 FROM legacy_import)", [], |row| row.get(0))?;
+    if imported {
+        // the rename didn't happen last time
+        return Ok(fs::rename(legacy_db, format!("{legacy_db}.imported")).await?);
+    }
+    let legacy: LegacyDB = match serde_json::from_str(&json_str) {
+        Ok(legacy) => legacy,
+        Err(e) => {
+            println!("Not importing {legacy_db}: {e}");
+            return Ok(());
+        }
+    };
+
+    let mut users = Vec::new();
+    for (username, id) in legacy.users {
+        let path = format!("{legacy_users}/{id}.json");
+        let parsed = storage::open_json(&path).await
+            .and_then(|content| serde_json::from_str::<User>(&content).map_err(AppError::from));
+        match parsed {
+            Ok(user) => users.push(user),
+            Err(e) => println!("Skipping user {username} ({path}): {e}"),
+        }
+    }
+
+    import_users(store, users)?;
+
+    fs::rename(legacy_db, format!("{legacy_db}.imported")).await?;
+    Ok(())
+}
+
+// all or nothing, along with the mark that the import ran
+fn import_users(store: &SqliteStore, users: Vec<User>) -> Res<()> {
+    let mut conn = store.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+    for mut user in users {
+        let inserted = tx.execute("INSERT OR IGNORE INTO users (id, username, password) VALUES (?1, ?2, ?3)", params![user.id, user.username, user.password])?;
+        if inserted == 0 {
+            println!("Skipping user {}: id {} or the name is taken", user.username, user.id);
+            continue;
+        }
+
+        // per-user title ids -> catalog ids
+        let mut title_map = HashMap::new();
+        for title in user.titles.iter_mut() {
+            // user files predate read marks, same reading of last_chap as migration 3
+            if title.read.is_empty() {
+                title.read = (0..title.last_chap.min(title.chapters.len() as u32)).collect();
+            }
+            // user files predate chapter numbers, and only had 0 for uncounted chapters
+            for chapter in title.chapters.iter_mut() {
+                if chapter.number.is_none() && chapter.volume.is_none() {
+                    (chapter.number, chapter.volume) = Chapter::parse_label(&chapter.t);
+                }
+                chapter.counted |= chapter.i > 0;
+            }
+            let catalog_id = SqliteStore::insert_catalog_title(&tx, &SystemTitle {
+                id: 0,
+                name: title.name.clone(),
+                url: title.url.clone(),
+                chap_prefix: title.chap_prefix.clone(),
+                last_updated: title.last_updated.clone(),
+                last_scanned: title.last_scanned.clone(),
+                meta: title.meta.clone(),
+                chapters: std::mem::take(&mut title.chapters),
+            })?;
+            title_map.insert(title.id, catalog_id);
+            title.id = catalog_id;
+        }
+        for ids in user.tags.values_mut() {
+            ids.retain(|id| title_map.contains_key(id));
+            ids.iter_mut().for_each(|id| *id = title_map[id]);
+        }
+        SqliteStore::insert_user_rows(&tx, &user)?;
+        println!("Imported user {} ({} titles)", user.username, user.titles.len());
+    }
+    tx.execute("INSERT INTO legacy_import (imported_at) VALUES (?1)", [timestamp::get_time()])?;
+    tx.commit()?;
+    Ok(())
+}
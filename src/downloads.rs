@@ -0,0 +1,305 @@
+use std::{collections::HashMap, ops::RangeInclusive, sync::{LazyLock, Mutex}, time::Duration};
+use chrono::{DateTime, Utc};
+use futures::{future, stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use crate::{
+    db,
+    error::{AppError, Res},
+    events::{self, Event},
+    library::{self, SystemTitle},
+    retention, shutdown,
+    storage::Storage,
+    web::{Fetcher, PageProgress},
+};
+
+/// How many jobs download at the same time
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub concurrency: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { concurrency: 2 }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed, // finished, but some chapters could not be downloaded
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<JobStatus> {
+        [JobStatus::Queued, JobStatus::Running, JobStatus::Done, JobStatus::Failed, JobStatus::Cancelled]
+            .into_iter()
+            .find(|known| known.as_str() == status)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+/// A range of chapters of one title, downloaded in order
+#[derive(Serialize, Clone, Debug)]
+pub struct Job {
+    pub id: i64,
+    #[serde(skip)]
+    pub user_id: u32,
+    pub title_id: u32,
+    pub from: u32, // chapter index, inclusive
+    pub to: u32, // chapter index, inclusive
+    pub status: JobStatus,
+    pub chapters_done: u32,
+    pub images_done: u32,
+    pub images_total: u32, // from the catalog, corrected as chapters are scraped
+    pub bytes: u64, // downloaded by this job, chapters already on disk count 0
+    pub errors: Vec<String>, // "chapter {index}: {error}", "chapter {index} page {page}: {error}"
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+struct Running {
+    job: Job,
+    cancel: CancellationToken,
+}
+
+// jobs being worked on, their progress is only saved to the database between chapters
+static RUNNING: LazyLock<Mutex<HashMap<i64, Running>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+// a job was queued
+static WAKE: Notify = Notify::const_new();
+
+// every change goes to the owner's event stream
+fn publish(job: &Job) {
+    events::send(job.user_id, Event::Download(job.clone()));
+}
+
+/// Resumes jobs a previous run left unfinished and starts `settings.concurrency` workers
+pub fn start(settings: Settings, storage: Storage, web: Fetcher) {
+    match db::store().requeue_running_jobs() {
+        Ok(0) => {}
+        Ok(resumed) => println!("Resuming {resumed} download jobs"),
+        Err(e) => println!("Could not resume download jobs: {e}"),
+    }
+    for _ in 0..settings.concurrency.max(1) {
+        shutdown::spawn(worker(storage.clone(), web.clone()));
+    }
+}
+
+async fn worker(storage: Storage, web: Fetcher) {
+    while !shutdown::is_stopping() {
+        match db::store().claim_next_job() {
+            Ok(Some(job)) => run(&storage, &web, job).await,
+            Ok(None) => {
+                if shutdown::unless_stopping(WAKE.notified()).await.is_none() {
+                    break;
+                }
+            }
+            Err(e) => {
+                println!("Download queue unavailable: {e}");
+                shutdown::unless_stopping(tokio::time::sleep(Duration::from_secs(5))).await;
+            }
+        }
+    }
+}
+
+/// Queues `chapters` of `title` for `user_id`
+pub fn enqueue(user_id: u32, title: &SystemTitle, chapters: RangeInclusive<u32>) -> Res<Job> {
+    if shutdown::is_stopping() {
+        return Err(AppError::Unavailable("server is shutting down".to_string()));
+    }
+    let images_total = chapters.clone().filter_map(|index| title.chapters.get(index as usize)).map(|chapter| chapter.i).sum();
+    let job = db::store().create_job(user_id, title.id, chapters, images_total, Utc::now())?;
+    WAKE.notify_one();
+    publish(&job);
+    Ok(job)
+}
+
+/// Like enqueue, unless a queued or running job of `user_id` already covers `chapters`
+pub fn ensure_queued(user_id: u32, title: &SystemTitle, chapters: RangeInclusive<u32>) -> Res<Job> {
+    let covering = list(user_id)?.into_iter().find(|job| {
+        !job.status.is_finished() && job.title_id == title.id && job.from <= *chapters.start() && job.to >= *chapters.end()
+    });
+    match covering {
+        Some(job) => Ok(job),
+        None => enqueue(user_id, title, chapters),
+    }
+}
+
+/// A job of `user_id`, with live progress while it runs
+pub fn get(user_id: u32, id: i64) -> Res<Job> {
+    let running = RUNNING.lock().unwrap().get(&id).map(|running| running.job.clone());
+    let job = match running {
+        Some(job) => Some(job),
+        None => db::store().load_job(id)?,
+    };
+    job.filter(|job| job.user_id == user_id).ok_or_else(|| AppError::not_found("Download job does not exist"))
+}
+
+/// Jobs of `user_id`, newest first
+pub fn list(user_id: u32) -> Res<Vec<Job>> {
+    let mut jobs = db::store().user_jobs(user_id)?;
+    let running = RUNNING.lock().unwrap();
+    for job in jobs.iter_mut() {
+        if let Some(live) = running.get(&job.id) {
+            *job = live.job.clone();
+        }
+    }
+    Ok(jobs)
+}
+
+/// Drops a queued job or stops a running one, its current chapter is rolled back.
+/// Finished jobs are returned unchanged.
+pub fn cancel(user_id: u32, id: i64) -> Res<Job> {
+    let job = get(user_id, id)?;
+    if job.status.is_finished() || !db::store().cancel_job(id, Utc::now())? {
+        return Ok(job);
+    }
+    // a worker that claimed the job but isn't in RUNNING yet sees the row instead
+    let running = RUNNING.lock().unwrap().get(&id).map(|running| running.cancel.clone());
+    match running {
+        Some(cancel) => cancel.cancel(),
+        None => publish(&get(user_id, id)?),
+    }
+    get(user_id, id)
+}
+
+/// The job now and after every change, ends once it finished
+pub fn watch(user_id: u32, id: i64) -> Res<impl Stream<Item = Job>> {
+    // subscribed before the snapshot so no change falls in between
+    let updates = events::subscribe(user_id).filter_map(move |event| future::ready(match event {
+        Event::Download(job) if job.id == id => Some(job),
+        Event::Lagged { .. } => get(user_id, id).ok(),
+        _ => None,
+    }));
+    let job = get(user_id, id)?;
+    Ok(stream::unfold(Some((Some(job), Box::pin(updates))), |state| async move {
+        let (next, mut updates) = state?;
+        let job = match next {
+            Some(job) => job,
+            None => updates.next().await?,
+        };
+        // a finished job is the last item
+        let state = (!job.status.is_finished()).then_some((None, updates));
+        Some((job, state))
+    }))
+}
+
+fn update(id: i64, change: impl FnOnce(&mut Job)) -> Option<Job> {
+    let job = {
+        let mut running = RUNNING.lock().unwrap();
+        let running = running.get_mut(&id)?;
+        change(&mut running.job);
+        running.job.clone()
+    };
+    publish(&job);
+    Some(job)
+}
+
+// saves the job and takes it off the running list
+fn finish(id: i64, status: JobStatus) {
+    let Some(job) = update(id, |job| {
+        job.status = status;
+        job.finished_at = status.is_finished().then(Utc::now);
+    }) else { return; };
+    if let Err(e) = db::store().save_job(&job) {
+        println!("Could not save download job {id}: {e}");
+    }
+    RUNNING.lock().unwrap().remove(&id);
+}
+
+/// Works through the job's chapters, skipping the ones already on disk.
+/// Shutdown puts the job back in the queue, it continues after a restart.
+async fn run(storage: &Storage, web: &Fetcher, mut job: Job) {
+    let id = job.id;
+    let cancel = shutdown::abort_token();
+    job.chapters_done = 0;
+    job.images_done = 0;
+    RUNNING.lock().unwrap().insert(id, Running { job: job.clone(), cancel: cancel.clone() });
+    publish(&job);
+    // cancelled between claim_next_job and the line above
+    if db::store().load_job(id).ok().flatten().is_some_and(|job| job.status == JobStatus::Cancelled) {
+        cancel.cancel();
+    }
+
+    let title = match library::get_title(job.title_id) {
+        Ok(title) => title,
+        Err(e) => {
+            update(id, |job| job.errors.push(format!("title {}: {e}", job.title_id)));
+            return finish(id, JobStatus::Failed);
+        }
+    };
+
+    for chapter_id in job.from..=job.to {
+        if shutdown::is_stopping() {
+            return finish(id, JobStatus::Queued);
+        }
+        if cancel.is_cancelled() {
+            break;
+        }
+        let Some(chapter) = title.chapters.get(chapter_id as usize) else {
+            update(id, |job| job.errors.push(format!("chapter {chapter_id}: no longer listed")));
+            continue;
+        };
+        retention::record_access(title.id, chapter_id);
+
+        if storage.has_chapter(title.id, chapter_id).await {
+            let pages = storage.get_num_images(title.id, chapter_id).await.unwrap_or(chapter.i);
+            update(id, |job| {
+                job.images_total = (job.images_total + pages).saturating_sub(chapter.i);
+                job.images_done += pages;
+                job.chapters_done += 1;
+            });
+            continue;
+        }
+
+        let expected = chapter.i;
+        let progress = move |progress| {
+            update(id, |job| match progress {
+                PageProgress::Found(pages) => job.images_total = (job.images_total + pages).saturating_sub(expected),
+                PageProgress::Saved(bytes) => {
+                    job.images_done += 1;
+                    job.bytes += bytes;
+                }
+                PageProgress::Failed(page, e) => job.errors.push(format!("chapter {chapter_id} page {page}: {e}")),
+            });
+        };
+        let url = format!("{}{}", title.chap_prefix, chapter.s);
+        let result = library::download_chapter(storage, web, title.id, chapter_id, url, cancel.clone(), progress).await;
+
+        let job = match result {
+            Ok(()) => update(id, |job| job.chapters_done += 1),
+            Err(_) if shutdown::is_stopping() => return finish(id, JobStatus::Queued),
+            Err(_) if cancel.is_cancelled() => break,
+            Err(e) => update(id, |job| job.errors.push(format!("chapter {chapter_id}: {e}"))),
+        };
+        if let Some(Err(e)) = job.map(|job| db::store().save_job(&job)) {
+            println!("Could not save download job {id}: {e}");
+        }
+    }
+
+    let failed = RUNNING.lock().unwrap().get(&id).is_some_and(|running| !running.job.errors.is_empty());
+    finish(id, match () {
+        _ if cancel.is_cancelled() => JobStatus::Cancelled,
+        _ if failed => JobStatus::Failed,
+        _ => JobStatus::Done,
+    });
+}
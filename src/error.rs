@@ -0,0 +1,130 @@
+use std::fmt;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+
+pub type Res<T> = Result<T, AppError>;
+
+/// Every failure a handler can report. Turns into `{ code, message }` with a matching status.
+#[derive(Debug)]
+pub enum AppError {
+    Network(String),    // source site unreachable or returned an error
+    Timeout(String),    // source site too slow
+    Parse(String),      // source page didn't look like we expected
+    NotFound(String),
+    Auth(String),
+    Storage(String),    // disk or database
+    BadRequest(String),
+    Unavailable(String), // shutting down
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+impl AppError {
+    pub fn parse(message: &str) -> AppError {
+        AppError::Parse(message.to_string())
+    }
+
+    pub fn not_found(message: &str) -> AppError {
+        AppError::NotFound(message.to_string())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Network(_) => "network",
+            AppError::Timeout(_) => "timeout",
+            AppError::Parse(_) => "parse",
+            AppError::NotFound(_) => "not_found",
+            AppError::Auth(_) => "auth",
+            AppError::Storage(_) => "storage",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Network(_) | AppError::Parse(_) => StatusCode::BAD_GATEWAY,
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Auth(_) => StatusCode::UNAUTHORIZED,
+            AppError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            AppError::Network(m) | AppError::Timeout(m) | AppError::Parse(m) | AppError::NotFound(m)
+            | AppError::Auth(m) | AppError::Storage(m) | AppError::BadRequest(m) | AppError::Unavailable(m) => m,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if self.status().is_server_error() {
+            println!("ERROR {self}");
+        }
+        let body = ErrorBody { code: self.code(), message: self.message().to_string() };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> AppError {
+        if e.is_timeout() {
+            return AppError::Timeout(e.to_string());
+        }
+        AppError::Network(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> AppError {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => AppError::NotFound(e.to_string()),
+            _ => AppError::Storage(e.to_string()),
+        }
+    }
+}
+
+// our own JSON files on disk
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> AppError {
+        AppError::Storage(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> AppError {
+        AppError::Storage(e.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for AppError {
+    fn from(e: zip::result::ZipError) -> AppError {
+        AppError::Storage(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for AppError {
+    fn from(e: tokio::task::JoinError) -> AppError {
+        AppError::Storage(format!("background task failed: {e}"))
+    }
+}
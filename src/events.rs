@@ -0,0 +1,69 @@
+use std::sync::{Arc, LazyLock};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::{chapter_diff::ChapterDiff, db, downloads::Job, shutdown, user::Title};
+
+/// Pushed to a user's /events stream, serialized as `{ "type": ..., ... }`
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// An update changed the chapter list: new chapters, or removed, renamed and re-uploaded ones
+    TitleUpdated { title_id: u32, name: String, diff: ChapterDiff },
+    /// A download job changed, see downloads::Job
+    Download(Job),
+    TitleAdded { title: Box<Title> },
+    TitleRemoved { title_id: u32 },
+    /// The stream fell behind and dropped events, the client should reload the user
+    Lagged { missed: u64 },
+}
+
+impl Event {
+    /// SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::TitleUpdated { .. } => "title_updated",
+            Event::Download(_) => "download",
+            Event::TitleAdded { .. } => "title_added",
+            Event::TitleRemoved { .. } => "title_removed",
+            Event::Lagged { .. } => "lagged",
+        }
+    }
+}
+
+struct Message {
+    users: Vec<u32>,
+    event: Event,
+}
+
+static BUS: LazyLock<broadcast::Sender<Arc<Message>>> = LazyLock::new(|| broadcast::channel(1024).0);
+
+/// Nobody listening is fine, events are only a hint to refresh
+pub fn send(user_id: u32, event: Event) {
+    BUS.send(Arc::new(Message { users: vec![user_id], event })).ok();
+}
+
+/// To every user following `title_id`
+pub fn send_to_followers(title_id: u32, event: Event) {
+    match db::store().follower_ids(title_id) {
+        Ok(users) if !users.is_empty() => { BUS.send(Arc::new(Message { users, event })).ok(); }
+        Ok(_) => {}
+        Err(e) => println!("Could not notify followers of title {title_id}: {e}"),
+    }
+}
+
+/// Events for `user_id` from now on, until shutdown
+pub fn subscribe(user_id: u32) -> impl Stream<Item = Event> {
+    let receiver = BUS.subscribe();
+    futures::stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) if message.users.contains(&user_id) => return Some((message.event.clone(), receiver)),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(missed)) => return Some((Event::Lagged { missed }, receiver)),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .take_until(shutdown::stopping())
+}
@@ -0,0 +1,397 @@
+use std::{io::Write, ops::RangeInclusive, path::Path};
+use serde::Deserialize;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+use crate::{
+    error::{AppError, Res},
+    library::SystemTitle,
+    storage::Storage,
+};
+
+/// Offline formats a chapter range can be packaged as
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Cbz,
+    Epub,
+    Pdf,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Cbz => "cbz",
+            Format::Epub => "epub",
+            Format::Pdf => "pdf",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Cbz => "application/vnd.comicbook+zip",
+            Format::Epub => "application/epub+zip",
+            Format::Pdf => "application/pdf",
+        }
+    }
+
+    /// Blocking, meant for spawn_blocking. Chapters must already be downloaded.
+    pub fn write<W: Write>(self, storage: &Storage, title: &SystemTitle, chapters: RangeInclusive<u32>, out: W) -> Res<()> {
+        match self {
+            Format::Cbz => write_cbz(storage, title, chapters, out),
+            Format::Epub => write_epub(storage, title, chapters, out),
+            Format::Pdf => write_pdf(storage, title, chapters, out),
+        }
+    }
+}
+
+/// "{name} {first}-{last}.{ext}", safe for a Content-Disposition header
+pub fn filename(title: &SystemTitle, chapters: &RangeInclusive<u32>, format: Format) -> String {
+    let name: String = title.name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == ' ' || c == '-' { c } else { '_' })
+        .collect();
+    format!("{} {}-{}.{}", name.trim(), chapters.start() + 1, chapters.end() + 1, format.extension())
+}
+
+/// A page ready to embed: always JPEG, other formats are re-encoded
+struct Page {
+    jpeg: Vec<u8>,
+    width: u32,
+    height: u32,
+    components: u8, // 1 gray, 3 rgb
+}
+
+fn load_page(path: &Path) -> Res<Page> {
+    let bytes = std::fs::read(path)?;
+    // downloads are saved as .jpeg whatever the source served
+    if let Some((width, height, components @ (1 | 3))) = jpeg_info(&bytes) {
+        return Ok(Page { jpeg: bytes, width, height, components });
+    }
+
+    let image = image::load_from_memory(&bytes)
+        .map_err(|e| AppError::Storage(format!("{} is not a readable image: {e}", path.display())))?
+        .to_rgb8();
+    let mut jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 90)
+        .encode_image(&image)
+        .map_err(|e| AppError::Storage(format!("could not re-encode {}: {e}", path.display())))?;
+    Ok(Page { jpeg, width: image.width(), height: image.height(), components: 3 })
+}
+
+/// Width, height and colour components from the first SOF marker
+fn jpeg_info(bytes: &[u8]) -> Option<(u32, u32, u8)> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut i = 2;
+    while i + 9 < bytes.len() {
+        if bytes[i] != 0xFF {
+            return None;
+        }
+        let marker = bytes[i + 1];
+        match marker {
+            0xFF => { i += 1; continue; } // fill byte
+            0x01 | 0xD0..=0xD7 => { i += 2; continue; } // no length
+            _ => {}
+        }
+        let length = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+        if matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let height = u16::from_be_bytes([bytes[i + 5], bytes[i + 6]]) as u32;
+            let width = u16::from_be_bytes([bytes[i + 7], bytes[i + 8]]) as u32;
+            return Some((width, height, bytes[i + 9]));
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+pub fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// ComicInfo.xml (Anansi schema v2.0) for a chapter range of a title
+fn comic_info(title: &SystemTitle, chapters: &RangeInclusive<u32>, page_count: usize) -> String {
+    let (start, end) = (*chapters.start(), *chapters.end());
+    let (name, number) = if start == end {
+        let text = title.chapters.get(start as usize).map_or(String::new(), |chapter| chapter.t.clone());
+        (text, (start + 1).to_string())
+    } else {
+        (format!("{} {}-{}", title.name, start + 1, end + 1), format!("{}-{}", start + 1, end + 1))
+    };
+    let mut date = title.last_updated.split('-');
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml += "<ComicInfo xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n";
+    xml += &format!("  <Title>{}</Title>\n", xml_escape(&name));
+    xml += &format!("  <Series>{}</Series>\n", xml_escape(&title.name));
+    xml += &format!("  <Number>{number}</Number>\n");
+    xml += &format!("  <Count>{}</Count>\n", title.chapters.len());
+    for tag in ["Year", "Month", "Day"] {
+        if let Some(value) = date.next().and_then(|value| value.parse::<u32>().ok()) {
+            xml += &format!("  <{tag}>{value}</{tag}>\n");
+        }
+    }
+    if let Some(synopsis) = &title.meta.synopsis {
+        xml += &format!("  <Summary>{}</Summary>\n", xml_escape(synopsis));
+    }
+    for (tag, names) in [("Writer", &title.meta.authors), ("Penciller", &title.meta.artists), ("Genre", &title.meta.genres)] {
+        if !names.is_empty() {
+            xml += &format!("  <{tag}>{}</{tag}>\n", xml_escape(&names.join(", ")));
+        }
+    }
+    xml += &format!("  <Web>{}</Web>\n", xml_escape(&title.url));
+    xml += &format!("  <PageCount>{page_count}</PageCount>\n");
+    xml += "  <Manga>Yes</Manga>\n";
+    xml += "</ComicInfo>\n";
+    xml
+}
+
+/// Writes a CBZ of already downloaded chapters to `out`.
+/// Blocking, meant for spawn_blocking. Pages are named so they sort in reading order.
+pub fn write_cbz<W: Write>(storage: &Storage, title: &SystemTitle, chapters: RangeInclusive<u32>, out: W) -> Res<()> {
+    let mut zip = ZipWriter::new_stream(out);
+    // images are already compressed
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    let mut page_count = 0;
+    for chapter_id in chapters.clone() {
+        for (page, path) in storage.chapter_pages(title.id, chapter_id)?.into_iter().enumerate() {
+            zip.start_file(format!("{:04}_{:03}.jpeg", chapter_id + 1, page + 1), options)?;
+            std::io::copy(&mut std::fs::File::open(path)?, &mut zip)?;
+            page_count += 1;
+        }
+    }
+
+    zip.start_file("ComicInfo.xml", options)?;
+    zip.write_all(comic_info(title, &chapters, page_count).as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+fn chapter_name(title: &SystemTitle, chapter_id: u32) -> String {
+    title.chapters.get(chapter_id as usize)
+        .map(|chapter| chapter.t.clone())
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| format!("Chapter {}", chapter_id + 1))
+}
+
+// the first exported chapter that has a language, sources that don't say are taken as English
+fn language(title: &SystemTitle, chapters: &RangeInclusive<u32>) -> String {
+    title.chapters.iter()
+        .skip(*chapters.start() as usize)
+        .take((chapters.end() - chapters.start() + 1) as usize)
+        .filter_map(|chapter| chapter.language.as_deref())
+        .find(|language| !language.trim().is_empty())
+        .unwrap_or("en")
+        .to_string()
+}
+
+/// Fixed layout EPUB 3, one page per image, with a nav (and NCX for older readers) listing chapters
+pub fn write_epub<W: Write>(storage: &Storage, title: &SystemTitle, chapters: RangeInclusive<u32>, out: W) -> Res<()> {
+    let mut zip = ZipWriter::new_stream(out);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    // must be the first entry
+    zip.start_file("mimetype", options)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", options)?;
+    zip.write_all(concat!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+        "<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n",
+        "  <rootfiles><rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/></rootfiles>\n",
+        "</container>\n",
+    ).as_bytes())?;
+
+    let mut pages = Vec::new(); // page ids in reading order
+    let mut toc = Vec::new(); // (chapter name, first page id)
+    for chapter_id in chapters.clone() {
+        for (n, path) in storage.chapter_pages(title.id, chapter_id)?.into_iter().enumerate() {
+            let page = load_page(&path)?;
+            let id = format!("p{:04}_{:03}", chapter_id + 1, n + 1);
+            if n == 0 {
+                toc.push((chapter_name(title, chapter_id), id.clone()));
+            }
+
+            zip.start_file(format!("OEBPS/images/{id}.jpeg"), options)?;
+            zip.write_all(&page.jpeg)?;
+            zip.start_file(format!("OEBPS/pages/{id}.xhtml"), options)?;
+            zip.write_all(format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                <!DOCTYPE html>\n\
+                <html xmlns=\"http://www.w3.org/1999/xhtml\">\n\
+                <head><title>{id}</title><meta name=\"viewport\" content=\"width={w}, height={h}\"/>\
+                <style>body {{ margin: 0; }} img {{ display: block; width: {w}px; height: {h}px; }}</style></head>\n\
+                <body><img src=\"../images/{id}.jpeg\" alt=\"\"/></body>\n\
+                </html>\n",
+                w = page.width, h = page.height,
+            ).as_bytes())?;
+            pages.push(id);
+        }
+    }
+    if pages.is_empty() {
+        return Err(AppError::not_found("No downloaded pages to export"));
+    }
+
+    let name = xml_escape(&title.name);
+    let identifier = format!("urn:md_api:{}:{}-{}", title.id, chapters.start() + 1, chapters.end() + 1);
+
+    let mut nav = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n");
+    nav += "<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n";
+    nav += &format!("<head><title>{name}</title></head>\n<body><nav epub:type=\"toc\" id=\"toc\"><ol>\n");
+    let mut ncx = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    ncx += "<ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">\n";
+    ncx += &format!("<head><meta name=\"dtb:uid\" content=\"{identifier}\"/></head>\n<docTitle><text>{name}</text></docTitle>\n<navMap>\n");
+    for (order, (chapter, page)) in toc.iter().enumerate() {
+        let chapter = xml_escape(chapter);
+        nav += &format!("<li><a href=\"pages/{page}.xhtml\">{chapter}</a></li>\n");
+        ncx += &format!(
+            "<navPoint id=\"nav{n}\" playOrder=\"{n}\"><navLabel><text>{chapter}</text></navLabel><content src=\"pages/{page}.xhtml\"/></navPoint>\n",
+            n = order + 1,
+        );
+    }
+    nav += "</ol></nav></body>\n</html>\n";
+    ncx += "</navMap>\n</ncx>\n";
+
+    let mut opf = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    opf += "<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"id\">\n";
+    opf += "<metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n";
+    opf += &format!("  <dc:identifier id=\"id\">{identifier}</dc:identifier>\n");
+    opf += &format!("  <dc:title>{name}</dc:title>\n");
+    opf += &format!("  <dc:language>{}</dc:language>\n", xml_escape(&language(title, &chapters)));
+    opf += &format!("  <dc:source>{}</dc:source>\n", xml_escape(&title.url));
+    opf += &format!("  <meta property=\"dcterms:modified\">{}</meta>\n", chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"));
+    opf += "  <meta property=\"rendition:layout\">pre-paginated</meta>\n";
+    opf += "  <meta property=\"rendition:spread\">none</meta>\n";
+    opf += &format!("  <meta name=\"cover\" content=\"img_{}\"/>\n", pages[0]);
+    opf += "</metadata>\n<manifest>\n";
+    opf += "  <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n";
+    opf += "  <item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\"/>\n";
+    for (n, page) in pages.iter().enumerate() {
+        let cover = if n == 0 { " properties=\"cover-image\"" } else { "" };
+        opf += &format!("  <item id=\"img_{page}\" href=\"images/{page}.jpeg\" media-type=\"image/jpeg\"{cover}/>\n");
+        opf += &format!("  <item id=\"{page}\" href=\"pages/{page}.xhtml\" media-type=\"application/xhtml+xml\"/>\n");
+    }
+    opf += "</manifest>\n<spine toc=\"ncx\">\n";
+    for page in &pages {
+        opf += &format!("  <itemref idref=\"{page}\"/>\n");
+    }
+    opf += "</spine>\n</package>\n";
+
+    zip.start_file("OEBPS/nav.xhtml", options)?;
+    zip.write_all(nav.as_bytes())?;
+    zip.start_file("OEBPS/toc.ncx", options)?;
+    zip.write_all(ncx.as_bytes())?;
+    zip.start_file("OEBPS/content.opf", options)?;
+    zip.write_all(opf.as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+/// Minimal PDF writer, objects are streamed out and the xref table written last
+struct PdfWriter<W: Write> {
+    out: W,
+    written: u64,
+    offsets: Vec<u64>, // by object id - 1
+}
+
+impl<W: Write> PdfWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> Res<()> {
+        self.out.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn reserve(&mut self) -> usize {
+        self.offsets.push(0);
+        self.offsets.len()
+    }
+
+    fn object(&mut self, id: usize, dictionary: &str, stream: Option<&[u8]>) -> Res<()> {
+        self.offsets[id - 1] = self.written;
+        self.write(format!("{id} 0 obj\n{dictionary}\n").as_bytes())?;
+        if let Some(stream) = stream {
+            self.write(b"stream\n")?;
+            self.write(stream)?;
+            self.write(b"\nendstream\n")?;
+        }
+        self.write(b"endobj\n")
+    }
+
+    fn finish(mut self, catalog: usize, info: usize) -> Res<()> {
+        let xref = self.written;
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            table += &format!("{offset:010} 00000 n \n");
+        }
+        table += &format!("trailer\n<< /Size {} /Root {catalog} 0 R /Info {info} 0 R >>\nstartxref\n{xref}\n%%EOF\n", self.offsets.len() + 1);
+        self.write(table.as_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// UTF-16BE hex string, so chapter names can be any language
+fn pdf_text(text: &str) -> String {
+    let mut hex = String::from("<FEFF");
+    for unit in text.encode_utf16() {
+        hex += &format!("{unit:04X}");
+    }
+    hex + ">"
+}
+
+/// One page per image (1px = 1pt), with a bookmark per chapter
+pub fn write_pdf<W: Write>(storage: &Storage, title: &SystemTitle, chapters: RangeInclusive<u32>, out: W) -> Res<()> {
+    let mut pdf = PdfWriter { out, written: 0, offsets: Vec::new() };
+    pdf.write(b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n")?;
+    let catalog = pdf.reserve();
+    let pages_root = pdf.reserve();
+    let outlines = pdf.reserve();
+    let info = pdf.reserve();
+
+    let mut page_ids = Vec::new();
+    let mut bookmarks = Vec::new(); // (chapter name, page object id)
+    for chapter_id in chapters {
+        for (n, path) in storage.chapter_pages(title.id, chapter_id)?.into_iter().enumerate() {
+            let page = load_page(&path)?;
+            let (image, content, page_id) = (pdf.reserve(), pdf.reserve(), pdf.reserve());
+            let color_space = if page.components == 1 { "/DeviceGray" } else { "/DeviceRGB" };
+            pdf.object(image, &format!(
+                "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {color_space} /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>",
+                page.width, page.height, page.jpeg.len(),
+            ), Some(&page.jpeg))?;
+            let draw = format!("q {} 0 0 {} 0 0 cm /Im0 Do Q", page.width, page.height);
+            pdf.object(content, &format!("<< /Length {} >>", draw.len()), Some(draw.as_bytes()))?;
+            pdf.object(page_id, &format!(
+                "<< /Type /Page /Parent {pages_root} 0 R /MediaBox [0 0 {} {}] /Resources << /XObject << /Im0 {image} 0 R >> >> /Contents {content} 0 R >>",
+                page.width, page.height,
+            ), None)?;
+            if n == 0 {
+                bookmarks.push((chapter_name(title, chapter_id), page_id));
+            }
+            page_ids.push(page_id);
+        }
+    }
+    if page_ids.is_empty() {
+        return Err(AppError::not_found("No downloaded pages to export"));
+    }
+
+    let items: Vec<usize> = bookmarks.iter().map(|_| pdf.reserve()).collect();
+    for (i, (name, page_id)) in bookmarks.iter().enumerate() {
+        let mut item = format!("<< /Title {} /Parent {outlines} 0 R /Dest [{page_id} 0 R /Fit]", pdf_text(name));
+        if i > 0 {
+            item += &format!(" /Prev {} 0 R", items[i - 1]);
+        }
+        if let Some(next) = items.get(i + 1) {
+            item += &format!(" /Next {next} 0 R");
+        }
+        pdf.object(items[i], &(item + " >>"), None)?;
+    }
+    pdf.object(outlines, &format!(
+        "<< /Type /Outlines /First {} 0 R /Last {} 0 R /Count {} >>",
+        items[0], items[items.len() - 1], items.len(),
+    ), None)?;
+
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{id} 0 R")).collect();
+    pdf.object(pages_root, &format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), page_ids.len()), None)?;
+    pdf.object(catalog, &format!("<< /Type /Catalog /Pages {pages_root} 0 R /Outlines {outlines} 0 R /PageMode /UseOutlines >>"), None)?;
+    pdf.object(info, &format!("<< /Title {} /Producer (md_api) >>", pdf_text(&title.name)), None)?;
+    pdf.finish(catalog, info)
+}
@@ -0,0 +1,214 @@
+use std::{io::SeekFrom, time::UNIX_EPOCH};
+use axum::{
+    body::StreamBody,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}};
+use tokio_util::io::ReaderStream;
+use crate::error::Res;
+
+// page URLs carry the chapter's suffix, another chapter (or a re-upload) gets a different URL
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+// covers are replaced when a title is re-added, so clients revalidate daily
+pub const REVALIDATE: &str = "public, max-age=86400";
+
+/// Content type from the file's magic bytes, everything is saved as .jpeg whatever the source served
+pub fn sniff_image_type(bytes: &[u8]) -> &'static str {
+    match bytes {
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f', ..] => "image/avif",
+        _ => "application/octet-stream",
+    }
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// "W/" prefixes are ignored, If-None-Match uses weak comparison
+fn etag_matches(list: &str, etag: &str) -> bool {
+    list.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// "bytes=0-99", "bytes=100-" or "bytes=-100" to an inclusive range.
+/// None means serve the whole file (unsupported or multiple ranges), Err means unsatisfiable.
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len.saturating_sub(1))),
+    };
+    if start > end || start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+/// Streams an image from disk with validators, conditional GET and single byte ranges
+pub async fn image_file(request: &HeaderMap, path: &str, cache_control: &'static str) -> Res<Response> {
+    let mut file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let len = metadata.len();
+    let modified: DateTime<Utc> = metadata.modified()?.into();
+    let mtime = modified.timestamp();
+    let etag = format!(
+        "\"{len:x}-{:x}\"",
+        metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default(),
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(&http_date(modified)).unwrap());
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    // If-None-Match wins over If-Modified-Since when both are sent
+    let not_modified = match header_str(request, header::IF_NONE_MATCH) {
+        Some(list) => etag_matches(list, &etag),
+        None => header_str(request, header::IF_MODIFIED_SINCE)
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .is_some_and(|since| mtime <= since.timestamp()),
+    };
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let mut magic = [0u8; 16];
+    let read = file.read(&mut magic).await?;
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(sniff_image_type(&magic[..read])));
+
+    // a stale If-Range means the client's partial copy is outdated, send everything
+    let range_valid = header_str(request, header::IF_RANGE).is_none_or(|tag| tag == etag);
+    let range = header_str(request, header::RANGE).filter(|_| range_valid).and_then(|range| parse_range(range, len));
+    let (status, start, end) = match range {
+        Some(Ok((start, end))) => {
+            headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes {start}-{end}/{len}")).unwrap());
+            (StatusCode::PARTIAL_CONTENT, start, end)
+        }
+        Some(Err(())) => {
+            headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes */{len}")).unwrap());
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+        None if len == 0 => return Ok((StatusCode::OK, headers).into_response()),
+        None => (StatusCode::OK, 0, len - 1),
+    };
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let body = StreamBody::new(ReaderStream::new(file.take(end - start + 1)));
+    Ok((status, headers, body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody;
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=100-", 1000), Some(Ok((100, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=999-999", 1000), Some(Ok((999, 999))));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_clamped() {
+        assert_eq!(parse_range("bytes=0-5000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(Ok((0, 999))));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=1000-1200", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=5-1", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+    }
+
+    #[test]
+    fn unsupported_ranges_serve_the_whole_file() {
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=abc-", 1000), None);
+        assert_eq!(parse_range("bytes=5", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn etags_compare_weakly() {
+        assert!(etag_matches("\"a\"", "\"a\""));
+        assert!(etag_matches("W/\"a\"", "\"a\""));
+        assert!(etag_matches("\"b\", \"a\"", "\"a\""));
+        assert!(etag_matches("*", "\"a\""));
+        assert!(!etag_matches("\"b\"", "\"a\""));
+    }
+
+    async fn page(path: &str, request: &[(header::HeaderName, &str)]) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let mut headers = HeaderMap::new();
+        for (name, value) in request {
+            headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        let response = image_file(&headers, path, IMMUTABLE).await.unwrap();
+        let (parts, mut body) = response.into_parts();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        (parts.status, parts.headers, bytes)
+    }
+
+    #[tokio::test]
+    async fn range_requests() {
+        let path = std::env::temp_dir().join(format!("md_api_file_response_{}.jpeg", std::process::id()));
+        let mut bytes = vec![0xFF, 0xD8, 0xFF];
+        bytes.extend(3..100u8);
+        tokio::fs::write(&path, &bytes).await.unwrap();
+        let path = path.to_str().unwrap();
+
+        let (status, headers, body) = page(path, &[(header::RANGE, "bytes=1-2")]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes 1-2/100");
+        assert_eq!(headers[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(body, vec![0xD8, 0xFF]);
+
+        let (status, headers, body) = page(path, &[(header::RANGE, "bytes=200-")]).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes */100");
+        assert!(body.is_empty());
+
+        // a partial copy from another version of the file gets the whole file
+        let (status, _, body) = page(path, &[(header::RANGE, "bytes=1-2"), (header::IF_RANGE, "\"stale\"")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.len(), 100);
+
+        let (_, headers, _) = page(path, &[]).await;
+        let etag = headers[header::ETAG].to_str().unwrap().to_string();
+        let (status, _, body) = page(path, &[(header::RANGE, "bytes=-10"), (header::IF_RANGE, &etag)]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, (90..100u8).collect::<Vec<_>>());
+
+        let (status, _, body) = page(path, &[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher, time::Duration};
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    Response, StatusCode,
+};
+use crate::{error::{AppError, Res}, shutdown};
+
+/// Timeouts and retries of every request to a source (pages, chapter lists, images, covers)
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration, // for the response, then for each chunk of its body
+    pub retries: u32, // after the first attempt
+    pub backoff: Duration, // before the first retry, doubled for each one after
+    pub max_backoff: Duration, // also caps Retry-After
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A reqwest client along with the retry settings of the requests sent through it
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::Client,
+    settings: Settings,
+}
+
+impl Client {
+    pub fn new(inner: reqwest::Client, settings: Settings) -> Client {
+        Client { inner, settings }
+    }
+}
+
+pub struct Fetched {
+    pub content_type: Option<String>,
+    pub bytes: Bytes,
+}
+
+// what went wrong in one attempt, and whether another one could help
+enum Failure {
+    Retry(AppError, Option<Duration>), // with the server's Retry-After
+    Fatal(AppError),
+}
+
+/// GET `url`, retrying connection errors, timeouts, 429 and 5xx with exponential backoff.
+/// The body is read within the attempt, a download that stalls halfway is retried too.
+/// 404 and 410 are NotFound, bodies over `max_bytes` fail without retrying.
+pub async fn get(client: &Client, url: &str, max_bytes: Option<usize>) -> Res<Fetched> {
+    let settings = &client.settings;
+    let mut attempt = 0;
+    loop {
+        let (error, retry_after) = match try_get(&client.inner, url, max_bytes, settings).await {
+            Ok(fetched) => return Ok(fetched),
+            Err(Failure::Fatal(error)) => return Err(error),
+            Err(Failure::Retry(error, retry_after)) => (error, retry_after),
+        };
+        if attempt >= settings.retries {
+            return Err(error);
+        }
+        let delay = retry_after.unwrap_or_else(|| backoff(settings, attempt)).min(settings.max_backoff);
+        attempt += 1;
+        // shutdown gives up on the wait rather than holding the process
+        if shutdown::unless_stopping(tokio::time::sleep(delay)).await.is_none() {
+            return Err(error);
+        }
+    }
+}
+
+pub async fn get_text(client: &Client, url: &str) -> Res<String> {
+    Ok(String::from_utf8_lossy(&get(client, url, None).await?.bytes).into_owned())
+}
+
+pub async fn get_bytes(client: &Client, url: &str) -> Res<Bytes> {
+    Ok(get(client, url, None).await?.bytes)
+}
+
+async fn try_get(client: &reqwest::Client, url: &str, max_bytes: Option<usize>, settings: &Settings) -> Result<Fetched, Failure> {
+    let read_timeout = settings.read_timeout;
+    let mut response = match tokio::time::timeout(read_timeout, client.get(url).send()).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) if e.is_connect() || e.is_timeout() || e.is_request() => return Err(Failure::Retry(e.into(), None)),
+        Ok(Err(e)) => return Err(Failure::Fatal(e.into())),
+        Err(_) => return Err(Failure::Retry(AppError::Timeout(format!("{url}: no response after {}s", read_timeout.as_secs())), None)),
+    };
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(match status {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Failure::Fatal(AppError::NotFound(format!("{url} not found upstream"))),
+            StatusCode::TOO_MANY_REQUESTS => Failure::Retry(AppError::Network(format!("{url} answered {status}")), retry_after(&response)),
+            _ if status.is_server_error() => Failure::Retry(AppError::Network(format!("{url} answered {status}")), retry_after(&response)),
+            _ => Failure::Fatal(AppError::Network(format!("{url} answered {status}"))),
+        });
+    }
+    let too_large = |max: usize| Failure::Fatal(AppError::Network(format!("{url}: larger than {max} bytes")));
+    if let Some(max) = max_bytes.filter(|max| response.content_length().is_some_and(|len| len > *max as u64)) {
+        return Err(too_large(max));
+    }
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string);
+
+    let mut body = Vec::new();
+    loop {
+        match tokio::time::timeout(read_timeout, response.chunk()).await {
+            Ok(Ok(Some(chunk))) => {
+                if let Some(max) = max_bytes.filter(|max| body.len() + chunk.len() > *max) {
+                    return Err(too_large(max));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(Failure::Retry(e.into(), None)),
+            Err(_) => return Err(Failure::Retry(AppError::Timeout(format!("{url}: body stalled for {}s", read_timeout.as_secs())), None)),
+        }
+    }
+    Ok(Fetched { content_type, bytes: body.into() })
+}
+
+// Retry-After is either seconds or an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    Some((at - Utc::now()).to_std().unwrap_or_default())
+}
+
+// `backoff` doubled per attempt, then a random half of it is taken off so clients don't retry in step
+fn backoff(settings: &Settings, attempt: u32) -> Duration {
+    let delay = settings.backoff.saturating_mul(1 << attempt.min(16)).min(settings.max_backoff);
+    let jitter = RandomState::new().hash_one(attempt) % 1000;
+    delay / 2 + delay / 2 * jitter as u32 / 1000
+}
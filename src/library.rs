@@ -1,204 +1,222 @@
-// use serde::{Deserialize, Serialize};
-// use std::error::Error;
-// use std::collections::HashSet;
-// use tokio::{
-//     fs::File,
-//     io::{AsyncReadExt, AsyncWriteExt},
-// };
-// use crate::{
-//     storage,
-//     storage::*,
-//     web,
-//     story::{SystemTitle, Chapter},
-//     timestamp::{get_time, self},
-//     latency::Latency,
-// };
-
-// #[derive(Serialize, Deserialize, Debug)]
-// pub struct Library {
-//     pub titles: Vec<SystemTitle>,
-// }
-
-// impl Library {
-//     pub async fn new() -> Result<Library, Box<dyn Error + Send + Sync>> {
-//         let mut file = File::open(format!("{}/library.json", TITLE_PATH)).await?;
-
-//         let mut content = String::new();
-//         file.read_to_string(&mut content).await.unwrap();
-
-//         let library: Library = serde_json::from_str(&content)?;
-//         Ok(library)
-//     }
-
-//     pub async fn save(&self) {
-//         let string = serde_json::to_string(&self).unwrap();
-//         let mut library_file = File::create(format!("{}/library.json", TITLE_PATH))
-//             .await
-//             .unwrap();
-//         library_file.write_all(string.as_bytes()).await.unwrap();
-//     }
-
-//     pub async fn add_title(&mut self, url: &str) -> Result<SystemTitle, Box<dyn Error>> {
-
-//         // check if title already exists
-//         if let Some(title) = self.get_title_by_url(url) {
-//             println!("Title already exists: {}", title.title);
-//             return Ok(title.clone());
-//         }
-
-//         // scrape basic details
-//         let (title_name, cover_url, links) = web::scout_title(url).await?;
-//         let title = SystemTitle {
-//             id: self.get_new_id(),
-//             title: title_name,
-//             last_updated: get_time(),
-//             url: url.to_string(),
-//             chapters: links.into_iter()
-//                 .map(|element| Chapter {
-//                     desc: element.0,
-//                     url: element.1,
-//                     // sufx: element.1.split('/').last().unwrap().to_string(),
-//                     })
-//                 .collect::<Vec<Chapter>>(),
-//         };
-
-//         // configure storage
-//         // - create title folder
-//         // - add to library.JSON
-//         // - download cover image
-        
-//         storage::setup_title(&title.id).await;
-
-//         let img_response = web::create_client().await.get(cover_url).send().await?;
-//         storage::save_cover(&title.id, img_response).await;
-
-//         self.titles.push(title.clone());
-//         self.save().await;
-
-//         Ok(title)
-//     }
-
-//     pub async fn remove_title(&mut self, id: u32) -> Result<(), Box<dyn Error>> {
-//         match self.get_title_by_id(id) {
-//             Some(title) => {
-//                 storage::remove_title(&title.id).await;
-//                 self.titles.retain(|title| title.id != id);
-//                 self.save().await;
-//             }
-//             None => { println!("Title does not exist: {}", id); }
-//         };
-//         Ok(())
-//     }
-
-//     pub async fn update_title(&mut self, id: u32) -> Result<(), Box<dyn Error>> {
-//         match self.titles.iter_mut().find(|title| title.id == id) {
-//             Some(mut title) => {
-//                 let links = web::get_chapters(&title.url).await?;
-//                 title.last_updated = get_time();
-//                 title.chapters = links.into_iter()
-//                     .map(|element| Chapter {
-//                         desc: element.0,
-//                         url: element.1,
-//                         // sufx: element.1.split('/').last().unwrap().to_string(),
-//                         })
-//                     .collect::<Vec<Chapter>>();
-//                 self.save().await;
-                
-//                 Ok(())
-//             }
-//             None => {
-//                 println!("Title does not exist: {}", id);
-//                 Err("Title does not exist".into())
-//             }
-//         }
-//     }
-
-//     pub async fn update_all_titles(&mut self) {
-//         for title in self.titles.iter_mut() {
-//             title.last_updated = get_time();
-//             let links = web::get_chapters(&title.url).await.unwrap();
-//             title.chapters = links.into_iter()
-//                 .map(|element| Chapter {
-//                     desc: element.0,
-//                     url: element.1,
-//                     // sufx: element.1.split('/').last().unwrap().to_string(),
-//                     })
-//                 .collect::<Vec<Chapter>>();
-//         }
-//     }
-
-//     pub async fn add_chapter(&self, title_id: &u32, chapter_id: &u32) -> Result<u32, Box<dyn Error>> {
-
-//         // what if title doesn't exist?
-//         // note: we assume chapter id exists
-//         match self.get_title_by_id(*title_id) {
-//             Some(title) => {
-//                 match storage::setup_chapter(title_id, chapter_id).await {
-//                     StorageResult::Success => {
-//                         let chapter_url = title.chapters.get(*chapter_id as usize).unwrap().url.as_str();
-//                         web::download_chapter(&format!("{TITLE_PATH}/{title_id}/{chapter_id}"), chapter_url).await.unwrap();
-//                     }
-//                     StorageResult::AlreadyExists => {
-//                         // println!("Chapter already downloaded: {}/{}", title_id, chapter_id);
-//                     }
-//                 }
-//                 Ok(storage::get_num_images(*title_id, *chapter_id).await)
-//             }
-//             None => {
-//                 println!("Title does not exist: {}", title_id);
-//                 Err("Title does not exist".into())
-//             }
-//         }
-//     }
-
-//     pub async fn remove_chapter(&self, title_id: &u32, chapter_id: &u32) -> Result<(), Box<dyn Error>> {
-//         match self.get_title_by_id(*title_id) {
-//             Some(_title) => {
-//                 storage::delete_chapter(title_id, chapter_id).await;
-//                 Ok(())
-//             }
-//             None => {
-//                 println!("Title does not exist: {}", title_id);
-//                 Err("Title does not exist".into())
-//             }
-//         }
-//     }
-
-//     pub async fn cleanup(&mut self) {
-//         // TODO: delete old titles (assume multiple users)
-//         // delete old chapters
-//         let MAX_CHAPTERS: usize = 3;
-//         let MAX_DAYS: i32 = 3;
-        
-//         for title in &self.titles {
-//             let days_since_updated = timestamp::get_duration(title.last_updated.clone(), timestamp::get_time());
-//             let mut chapters = storage::get_chapters(title.id).await;
-
-//             if days_since_updated >= MAX_DAYS {
-//                 storage::clear_title(&title.id).await;
-//             }
-//             else if chapters.len() >= MAX_CHAPTERS {
-//                 chapters.sort();
-//                 for i in 0..(chapters.len() - MAX_CHAPTERS) {
-//                     storage::delete_chapter(&title.id, &chapters[i]).await;
-//                 }
-//             }
-//         }
-//     }
-
-//     fn get_title_by_id(&self, id: u32) -> Option<&SystemTitle> {
-//         self.titles.iter().find(|title| title.id == id)
-//     }
-//     fn get_title_by_url(&self, url: &str) -> Option<&SystemTitle> {
-//         self.titles.iter().find(|title| title.url == url)
-//     }
-//     fn get_new_id(&self) -> u32 {
-//         let ids = self.titles.iter().map(|title| title.id).collect::<Vec<u32>>();
-//         let ids_set: HashSet<u32> = HashSet::from_iter(ids);
-//         let mut new_id: u32 = 0;
-//         while ids_set.contains(&new_id) {
-//             new_id += 1;
-//         }
-//         new_id
-//     }
-// }
+use std::{collections::HashMap, ops::RangeInclusive, sync::{Arc, LazyLock, Mutex}};
+use serde::Serialize;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, OwnedRwLockReadGuard, RwLock};
+use tokio_util::sync::CancellationToken;
+use crate::{
+    chapter_diff::ChapterDiff,
+    db,
+    error::{AppError, Res},
+    events::{self, Event},
+    page_counts,
+    retention,
+    shutdown,
+    storage::Storage,
+    timestamp,
+    user::{Chapter, TitleMeta},
+    web::{self, Fetcher, PageProgress},
+};
+
+/// Server-wide copy of a title, shared by every user following it.
+/// Users only keep references plus their own progress and tags.
+#[derive(Serialize, Debug, Clone)]
+pub struct SystemTitle {
+    pub id: u32,
+    pub name: String,
+    pub url: String,
+    pub chap_prefix: String, // "...com/"
+    pub last_updated: String, // Actual Release Date
+    pub last_scanned: String, // When Axum scanned
+    pub meta: TitleMeta,
+    pub chapters: Vec<Chapter>,
+}
+
+type Locks<K, L = AsyncMutex<()>> = LazyLock<Mutex<HashMap<K, Arc<L>>>>;
+
+// held while a title is refreshed
+static REFRESHING: Locks<u32> = LazyLock::new(Default::default);
+// written while an update saves a title and moves its chapter folders, read while a page is opened
+static REMAPPING: Locks<u32, RwLock<()>> = LazyLock::new(Default::default);
+// held while a chapter is downloaded, by (title, chapter)
+static DOWNLOADING: Locks<(u32, u32)> = LazyLock::new(Default::default);
+
+/// Returns the catalog entry for `url`, scraping it only if no one follows it yet
+pub async fn add_title(storage: &Storage, web: &Fetcher, url: &str) -> Res<SystemTitle> {
+    if let Some(id) = db::store().find_catalog_title(url)? {
+        return get_title(id);
+    }
+
+    let web::WebResult {
+        title,
+        chap_prefix,
+        last_updated,
+        meta,
+        chapters,
+        cover
+    } = web.extract_title(url).await?;
+
+    let mut title = SystemTitle {
+        id: 0,
+        name: title,
+        url: url.to_string(),
+        chap_prefix,
+        last_updated,
+        last_scanned: timestamp::get_time(),
+        meta,
+        chapters,
+    };
+    title.id = db::store().add_catalog_title(&title)?;
+    storage.save_cover(title.id, cover).await?;
+    page_counts::wake();
+
+    Ok(title)
+}
+
+pub fn get_title(id: u32) -> Res<SystemTitle> {
+    db::store().load_catalog_title(id)?.ok_or_else(|| AppError::not_found("Title Does Not Exist"))
+}
+
+/// Rescrapes the chapter list, saves it, moves progress and downloads along with their chapters
+/// and tells followers what changed. The diff is empty if nothing did.
+/// One refresh per title at a time; once the scrape is in, the rest runs to completion
+/// even if the caller is dropped (closed request, shutdown).
+pub async fn update_title(storage: &Storage, web: &Fetcher, id: u32) -> Res<ChapterDiff> {
+    let guard = lock_title(id).await;
+    let mut title = get_title(id)?;
+    web.update_title(&mut title).await?;
+
+    let storage = storage.clone();
+    shutdown::spawn(async move {
+        let _guard = guard;
+        let remapping = REMAPPING.lock().unwrap().entry(id).or_default().clone();
+        let _remapping = remapping.write().await;
+        let diff = db::store().save_catalog_title(&mut title)?;
+        if diff.is_empty() {
+            return Ok(diff);
+        }
+        if diff.moves_indexes() || !diff.reuploaded.is_empty() {
+            storage.remap_chapters(title.id, &diff).await?;
+        }
+        if !diff.added.is_empty() || !diff.reuploaded.is_empty() {
+            page_counts::wake();
+        }
+        events::send_to_followers(title.id, Event::TitleUpdated { title_id: title.id, name: title.name.clone(), diff: diff.clone() });
+        Ok(diff)
+    }).await?
+}
+
+// a second refresh would diff against the list the first one is replacing
+async fn lock_title(id: u32) -> OwnedMutexGuard<()> {
+    let lock = REFRESHING.lock().unwrap().entry(id).or_default().clone();
+    lock.lock_owned().await
+}
+
+/// Blocks updates from moving the title's chapters while held: between checking which chapter
+/// sits at an index and opening its pages
+pub async fn hold_chapters(id: u32) -> OwnedRwLockReadGuard<()> {
+    let lock = REMAPPING.lock().unwrap().entry(id).or_default().clone();
+    lock.read_owned().await
+}
+
+/// Chapter range to export, the whole title when neither end is given
+pub fn chapter_range(title: &SystemTitle, from: Option<u32>, to: Option<u32>) -> Res<RangeInclusive<u32>> {
+    let last = (title.chapters.len() as u32).checked_sub(1).ok_or_else(|| AppError::not_found("Title has no chapters"))?;
+    let range = from.unwrap_or(0)..=to.unwrap_or(last);
+    if range.is_empty() || *range.end() > last {
+        return Err(AppError::BadRequest(format!("Chapters {}-{} out of range (0-{last})", range.start(), range.end())));
+    }
+    Ok(range)
+}
+
+/// Chapters of the range that aren't on disk yet. The others count as used, so retention
+/// leaves them alone while they are exported.
+pub async fn missing_chapters(storage: &Storage, title: &SystemTitle, chapters: RangeInclusive<u32>) -> Vec<u32> {
+    let mut missing = Vec::new();
+    for chapter_id in chapters {
+        if storage.has_chapter(title.id, chapter_id).await {
+            retention::record_access(title.id, chapter_id);
+        } else {
+            missing.push(chapter_id);
+        }
+    }
+    missing
+}
+
+/// Downloads a chapter on a task shutdown waits for, so a dropped request doesn't cut it short.
+/// Pages land in a temporary folder that only replaces the chapter once complete. If some pages
+/// failed it is kept so the next attempt only fetches those; if the chapter page could not be read,
+/// or once `cancel` fires, it is deleted instead. During shutdown it is kept either way, startup
+/// only leaves the folders of chapters a queued job will download again (see Storage::remove_partial_files).
+pub async fn download_chapter(
+    storage: &Storage,
+    web: &Fetcher,
+    title_id: u32,
+    chapter_id: u32,
+    url: String,
+    cancel: CancellationToken,
+    progress: impl FnMut(PageProgress) + Send + 'static,
+) -> Res<()> {
+    if shutdown::is_stopping() {
+        return Err(AppError::Unavailable("server is shutting down".to_string()));
+    }
+    storage.setup_title(&title_id).await?;
+    let (storage, web) = (storage.clone(), web.clone());
+    shutdown::spawn(async move {
+        // downloads of the same chapter share its .part folder, the second one waits
+        let _guard = lock_chapter(title_id, chapter_id).await;
+        if storage.has_chapter(title_id, chapter_id).await {
+            return Ok(());
+        }
+        let dir = storage.begin_chapter(title_id, chapter_id).await?;
+        let result = tokio::select! {
+            result = web.download_chapter(&dir, &url, progress) => result,
+            _ = cancel.cancelled() => Err(AppError::Unavailable("download cancelled".to_string())),
+        };
+        match result {
+            Ok(0) => storage.commit_chapter(title_id, chapter_id).await,
+            Ok(failed) => Err(AppError::Network(format!("{failed} page(s) could not be downloaded"))),
+            // shutdown requeues the job, its pages wait for the restart
+            Err(e) if shutdown::is_stopping() => Err(e),
+            Err(e) => {
+                storage.discard_chapter(title_id, chapter_id).await?;
+                Err(e)
+            }
+        }
+    }).await?
+}
+
+async fn lock_chapter(title_id: u32, chapter_id: u32) -> OwnedMutexGuard<()> {
+    let lock = DOWNLOADING.lock().unwrap().entry((title_id, chapter_id)).or_default().clone();
+    lock.lock_owned().await
+}
+
+/// Drops a title from the catalog (with its cover and downloads) once nobody follows it
+pub async fn release_title(storage: &Storage, id: u32) -> Res<()> {
+    // a follow landing now keeps the title
+    if !db::store().delete_catalog_title(id)? {
+        return Ok(());
+    }
+    REFRESHING.lock().unwrap().remove(&id);
+    REMAPPING.lock().unwrap().remove(&id);
+    DOWNLOADING.lock().unwrap().retain(|(title_id, _), _| *title_id != id);
+    storage.remove_title(&id).await?;
+    storage.remove_cover(id).await
+}
+
+/// Covers are keyed by catalog id, titles migrated from per-user storage need theirs again
+pub async fn restore_missing_covers(storage: Storage, web: Fetcher) {
+    let Ok(ids) = db::store().catalog_ids() else { return; };
+    for id in ids {
+        if storage.has_cover(id).await {
+            continue;
+        }
+        if let Err(e) = restore_cover(&storage, &web, id).await {
+            println!("Could not restore cover for title {id}: {e}");
+        }
+    }
+}
+
+async fn restore_cover(storage: &Storage, web: &Fetcher, id: u32) -> Res<()> {
+    let title = get_title(id)?;
+    let cover = web.fetch_cover(&title.url).await?;
+    storage.save_cover(id, cover).await
+}
@@ -1,40 +1,110 @@
-use std::time::Duration;
 
-use tokio::{fs::{File, self}, io::AsyncReadExt, sync::RwLock, signal};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 use axum::{
-    extract::{Query, Path},
-    response::Json,
+    extract::{Query, Path, State},
+    response::{sse::{Event as SseEvent, KeepAlive, Sse}, IntoResponse, Json},
     routing::{get, post},
     Router,
-    body::Body,
-    http::StatusCode,
+    body::StreamBody,
+    http::{header, HeaderMap, StatusCode},
 };
+use futures::{Stream, StreamExt};
+use tokio_util::io::{ReaderStream, SyncIoBridge};
 // use axum_macros::debug_handler;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tower_http::cors;
 
 mod library;
+mod auth;
 mod user;
 mod timestamp;
 mod web;
+mod proxy;
+mod cache;
 mod storage;
+mod file_response;
+mod export;
+mod opds;
 mod latency;
+mod source;
+mod manganato;
+mod selector_source;
+mod error;
+mod db;
+mod scheduler;
+mod retention;
+mod config;
+mod shutdown;
+mod downloads;
+mod events;
+mod chapter_diff;
+mod page_counts;
+mod http;
 
 // use library::*;
 use user::*;
+use auth::{AdminUser, AuthUser, Keys, OpdsUser, StreamUser};
+use events::Event;
+use error::{AppError, Res};
+use config::{AppState, Config};
+use storage::Storage;
+use web::Fetcher;
 
-static UPDATING_CHAPTERS: RwLock<bool> = RwLock::const_new(false);
-const MAX_AGE_SECONDS: u64 = 60 * 30; // 30m
 
 #[tokio::main]
 async fn main() {
-    // cleanup loop
-    tokio::spawn(async {
-        loop {
-            clean().await;
-            tokio::time::sleep(Duration::from_secs(MAX_AGE_SECONDS)).await;
-        }
+    // md_api.toml, MD_API_* env vars and flags
+    let config = config::load(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(2);
+    });
+    // folders, source requests and session tokens, for the handlers and background tasks
+    let state = AppState {
+        config: Arc::new(config.clone()),
+        storage: Storage::new(&config.paths),
+        web: Fetcher::new(&config.http),
+        keys: Keys::load(&config.paths.session_key).await,
+    };
+
+    // users, titles and progress
+    db::init(&config.paths, &state.storage).await.expect("could not open database");
+
+    // proxied images
+    if config.cache.budget_mb == 0 {
+        println!("Image cache disabled by config");
+    } else if let Err(e) = cache::init(&config.paths.cache, config.cache.budget_bytes()).await {
+        println!("Image cache disabled: {e}");
+    }
+
+    // scrapers for supported sites
+    let referer = header::HeaderValue::from_str(&config.http.manganato_referer).expect("validated with the config");
+    source::register(Arc::new(manganato::Manganato { referer }));
+    source::load_definitions(&config.paths.sources).await;
+
+    // pages and covers a killed process left half written, chapters of queued downloads keep the pages they have
+    let unfinished = db::store().unfinished_jobs().unwrap_or_else(|e| {
+        println!("Could not list unfinished downloads: {e}");
+        Vec::new()
     });
+    let resumed = |title_id, chapter_id| unfinished.iter().any(|job| job.title_id == title_id && (job.from..=job.to).contains(&chapter_id));
+    if let Err(e) = state.storage.remove_partial_files(resumed).await {
+        println!("Could not clean up unfinished downloads: {e}");
+    }
+
+    // covers lost when titles moved into the shared catalog
+    shutdown::spawn(shutdown::unless_stopping(library::restore_missing_covers(state.storage.clone(), state.web.clone())));
+
+    // keep followed titles up to date
+    scheduler::start(config.scheduler.settings(), state.storage.clone(), state.web.clone());
+
+    // queued chapter downloads, including the ones a restart interrupted
+    downloads::start(config.downloads.settings(), state.storage.clone(), state.web.clone());
+
+    // page counts of chapters added without them
+    page_counts::start(config.page_counts.settings(), state.storage.clone(), state.web.clone());
+
+    // evict downloaded chapters nobody needs
+    retention::start(config.retention.policy(), state.storage.clone());
 
     // CORS setup
     let cors = cors::CorsLayer::permissive();
@@ -45,10 +115,19 @@ async fn main() {
     .route("/register", post(register_handler))
     .route("/login", post(login_handler))
     .route("/save_user", post(save_user_handler))
+    .route("/feed", get(feed_handler))
+    .route("/library", get(library_handler))
+    .route("/events", get(events_handler))
+
+    // reading progress endpoints
+    .route("/progress/read", post(mark_read_handler))
+    .route("/progress/read_range", post(mark_range_handler))
+    .route("/progress/page", post(set_page_handler))
     
     // image-related endpoints
     .route("/cover/:title_id", get(cover_handler))
-    .route("/img/:title_id/:chapter_id/:image_id", get(image_request))
+    .route("/img/:title_id/:chapter_id/:suffix/:image_id", get(image_request))
+    .route("/pages/:title_id/:chapter_id", get(page_count_handler))
     .route("/image_sources", get(srcs_handler))
     .route("/proxy", get(proxy_handler))
     
@@ -56,54 +135,82 @@ async fn main() {
     .route("/new_title", post(new_title_handler))
     .route("/remove_title", post(remove_title_handler))
     .route("/download_chapter", post(download_chapter_handler))
+    .route("/downloads", get(list_downloads_handler).post(enqueue_download_handler))
+    .route("/downloads/:job_id", get(download_handler).delete(cancel_download_handler))
+    .route("/downloads/:job_id/events", get(download_events_handler))
     .route("/update_title", post(update_title_handler))
 
-    .layer(cors);
-    // run it with hyper on localhost:3000
-    let server = axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service());
-
+    // export endpoints
+    .route("/export/:format/:title_id", get(export_handler))
+
+    // OPDS catalog for reader apps
+    .route("/opds", get(opds_root_handler))
+    .route("/opds/titles", get(opds_titles_handler))
+    .route("/opds/titles/:title_id", get(opds_title_handler))
+
+    // admin endpoints
+    .route("/admin/scheduler", get(scheduler_status_handler))
+    .route("/admin/scheduler/run", post(scheduler_run_handler))
+    .route("/admin/cache", get(cache_stats_handler))
+    .route("/admin/retention", get(retention_report_handler))
+    .route("/admin/retention/run", post(retention_run_handler))
+    .route("/admin/retention/pin", post(pin_title_handler))
+    .route("/admin/config", get(config_handler))
+
+    .layer(cors)
+    .with_state(state);
+    // run it with hyper on the configured address
+    println!("Listening on {}", config.bind);
+    let server = axum::Server::bind(&config.bind.parse().expect("validated with the config"))
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown::stopping());
+    let mut server = tokio::spawn(server);
+
+    // stop accepting connections, then let open requests and downloads finish
     tokio::select! {
-        _ = server => { println!("FATAL: Server crashed"); },
-        _ = signal::ctrl_c() => {
-            println!("Received Ctrl-C SIG, saving and shutting down server...");
-        },
+        result = &mut server => { println!("FATAL: Server crashed: {result:?}"); return; },
+        _ = shutdown::signal() => {},
     }
+    let server = async { server.await.ok(); };
+    shutdown::drain(server, Duration::from_secs(config.shutdown_grace_secs)).await;
 }
 
-async fn clean() {
-    // scan titles and delete unused ones
-    let mut title_dirs = fs::read_dir("./public/titles").await.unwrap();
-    while let Some(dir) = title_dirs.next_entry().await.unwrap() {
-        if dir.metadata().await.unwrap().modified().unwrap().elapsed().unwrap().as_secs() > MAX_AGE_SECONDS {
-            fs::remove_dir_all(dir.path()).await.unwrap();
-        }
-    }
-}
 
 #[derive(Deserialize)]
 struct RegisterBody {
     username: String,
     password: String,
     action: String,
+    new_password: Option<String>, // change_password only
 }
-async fn register_handler(Json(RegisterBody { username, password, action}): Json<RegisterBody>) -> StatusCode {
+async fn register_handler(State(storage): State<Storage>, Json(RegisterBody { username, password, action, new_password }): Json<RegisterBody>) -> Res<StatusCode> {
     match action.as_str() {
         "register" => {
-            let Some(user) = User::new(username, password).await else { return StatusCode::BAD_REQUEST; };
-            user.save_to_disk().await.unwrap();
+            User::new(username, &password)?;
         }
         "unregister" => {
-            let Some(user) = User::from(&username).await else { return StatusCode::BAD_REQUEST; };
-            if user.password == password {
-                user.delete_from_disk().await.unwrap();
-            } else {
-                return StatusCode::BAD_REQUEST;
+            let account = Account::from(&username)?;
+            if !account.check_password(&password) {
+                return Err(AppError::Auth("Wrong Password".to_string()));
+            }
+            let user = account.user()?;
+            account.delete()?;
+            for title in &user.titles {
+                library::release_title(&storage, title.id).await?;
             }
         }
-        _ => {}
+        // signs out every session
+        "change_password" => {
+            let new_password = new_password.ok_or_else(|| AppError::BadRequest("Missing new_password".to_string()))?;
+            let mut account = Account::from(&username)?;
+            if !account.check_password(&password) {
+                return Err(AppError::Auth("Wrong Password".to_string()));
+            }
+            account.set_password(&new_password)?;
+        }
+        _ => return Err(AppError::BadRequest(format!("Unknown action: {action}"))),
     }
-    StatusCode::OK
+    Ok(StatusCode::OK)
 }
 
 
@@ -112,28 +219,31 @@ struct LoginBody {
     username: String,
     password: String,
 }
-async fn login_handler(Json(LoginBody { username, password }): Json<LoginBody>) -> Json<User> {
-    if let Some(user) = User::from(&username).await {
-        if user.password == password {
-            return Json(user);
-        } else { return Json(User::empty_with_message("Wrong Password".to_string())); }
+#[derive(Serialize)]
+struct Session {
+    token: String,
+    user: User,
+}
+async fn login_handler(State(keys): State<Keys>, Json(LoginBody { username, password }): Json<LoginBody>) -> Res<Json<Session>> {
+    let mut account = Account::from(&username)?;
+    if !account.check_password(&password) {
+        return Err(AppError::Auth("Wrong Password".to_string()));
+    }
+
+    // upgrade accounts from before password hashing
+    if auth::is_legacy_password(&account.password) {
+        account.set_password(&password)?;
     }
 
-    Json(User::empty_with_message("User Does Not Exist".to_string()))
+    Ok(Json(Session {
+        token: keys.issue_token(&account),
+        user: account.user()?.without_password(),
+    }))
 }
 
 
-async fn cover_handler(Path(title_id): Path<u32>) -> axum::http::Response<Body> {
-    if let Ok(mut file) = File::open(format!("./public/covers/{}.jpeg", title_id)).await {
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf).await.unwrap();
-        let body = Body::from(buf);
-        return axum::http::Response::builder()
-            .status(StatusCode::OK)
-            .header(axum::http::header::CONTENT_TYPE, "image/jpeg")
-            .body(body).unwrap();
-    }
-    axum::http::Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap()
+async fn cover_handler(State(storage): State<Storage>, Path(title_id): Path<u32>, headers: HeaderMap) -> Res<axum::response::Response> {
+    file_response::image_file(&headers, &format!("{}/{}.jpeg", storage.cover_path(), title_id), file_response::REVALIDATE).await
 }
 
 
@@ -141,8 +251,8 @@ async fn cover_handler(Path(title_id): Path<u32>) -> axum::http::Response<Body>
 struct ImageUrlsQuery {
     chapter_url: String
 }
-async fn srcs_handler(Query(query): Query<ImageUrlsQuery>) -> Json<Vec<String>> {
-    Json(web::get_images_src(&query.chapter_url).await.unwrap())
+async fn srcs_handler(State(web): State<Fetcher>, Query(query): Query<ImageUrlsQuery>) -> Res<Json<Vec<String>>> {
+    Ok(Json(web.get_images_src(&query.chapter_url).await?))
 }
 
 
@@ -150,129 +260,335 @@ async fn srcs_handler(Query(query): Query<ImageUrlsQuery>) -> Json<Vec<String>>
 struct ProxyQuery {
     url: String
 }
-async fn proxy_handler(Query(query): Query<ProxyQuery>) -> axum::http::Response<Body> {
-    let client = web::create_client().await;
-    let data = client.get(&query.url).send().await.unwrap().bytes().await.unwrap();
-
-    let response = axum::http::Response::builder()
-        .status(StatusCode::OK)
-        .header(axum::http::header::CONTENT_TYPE, "image/jpeg")
-        .body(Body::from(data))
-        .unwrap();
-
-    response
+async fn proxy_handler(State(config): State<Arc<Config>>, State(web): State<Fetcher>, Query(query): Query<ProxyQuery>) -> Res<axum::response::Response> {
+    let (image, status) = match cache::get(&query.url).await {
+        Some(image) => (image, "HIT"),
+        None => {
+            let image = proxy::fetch_image(&web, &query.url, &config.proxy).await?;
+            cache::put(&query.url, &image).await;
+            (image, "MISS")
+        }
+    };
+    Ok(([(header::CONTENT_TYPE, image.content_type.as_str()), (header::HeaderName::from_static("x-cache"), status)], image.bytes).into_response())
 }
 
 
 #[derive(Deserialize)]
 struct NewTitleBody {
-    username: String,
     url: String,
 }
-async fn new_title_handler(Json(NewTitleBody { username, url }): Json<NewTitleBody>) -> Json<user::User> {
-    let Some(mut user) = User::from(&username).await else {
-        return Json(User::empty_with_message("User Does Not Exist".to_string()));
-    };
-
-    // ? What if another User has this title?
-
+async fn new_title_handler(AuthUser(account): AuthUser, State(storage): State<Storage>, State(web): State<Fetcher>, Json(NewTitleBody { url }): Json<NewTitleBody>) -> Res<Json<user::User>> {
+    let mut user = account.user()?;
     if !user.has_title_url(&url) {
-        let web_result = web::extract_title(&url).await;
-        let web::WebResult {
-            title,
-            chap_prefix,
-            last_updated,
-            chapters,
-            cover
-        } = web_result;
-
-        // Save Details to User
-        let new_title_id = user.add_title(title, url, chap_prefix, last_updated, chapters).unwrap();
-
-        // Save Cover to Disk
-        storage::save_cover(new_title_id, cover).await;
-        
-        // Save User
-        user.save_to_disk().await.unwrap();
+        // scraped once server-wide, other followers reuse it
+        let title = library::add_title(&storage, &web, &url).await?;
+        if account.follow(&title)? {
+            user = account.user()?;
+            if let Some(added) = user.titles.iter().find(|t| t.id == title.id) {
+                events::send(user.id, Event::TitleAdded { title: Box::new(added.clone()) });
+            }
+        }
     }
 
-    Json(user)
+    Ok(Json(user.without_password()))
 }
 
 
 
 #[derive(Deserialize)]
 struct RemoveTitleBody {
-    username: String,
     id: u32,
 }
-async fn remove_title_handler(Json(RemoveTitleBody { username, id }): Json<RemoveTitleBody>) -> StatusCode  {
-    let Some(mut user) = User::from(&username).await else {
-        return StatusCode::NOT_FOUND;
-    };
-    user.remove_title(id);
-    user.save_to_disk().await.unwrap();
-    StatusCode::OK  
+async fn remove_title_handler(AuthUser(user): AuthUser, State(storage): State<Storage>, Json(RemoveTitleBody { id }): Json<RemoveTitleBody>) -> Res<StatusCode> {
+    if user.unfollow(id)? {
+        events::send(user.id, Event::TitleRemoved { title_id: id });
+    }
+    library::release_title(&storage, id).await?;
+    Ok(StatusCode::OK)
 }
 
 
+// queues a single chapter, same as POST /downloads with from = to
 #[derive(Deserialize)]
 struct DownloadChapterBody {
     title_id: u32,
     chapter_id: u32,
-    url: String,
 }
-async fn download_chapter_handler(Json(DownloadChapterBody { title_id, chapter_id, url}): Json<DownloadChapterBody>) -> StatusCode {
-    storage::setup_title(&title_id).await;
-    storage::setup_chapter(&title_id, &chapter_id).await;
-    if let Ok(()) = web::download_chapter(&format!("./public/titles/{title_id}/{chapter_id}"), &url).await {
-        StatusCode::OK
-    } else {
-        StatusCode::INTERNAL_SERVER_ERROR
+async fn download_chapter_handler(AuthUser(user): AuthUser, Json(DownloadChapterBody { title_id, chapter_id }): Json<DownloadChapterBody>) -> Res<(StatusCode, Json<downloads::Job>)> {
+    enqueue_download(&user, title_id, Some(chapter_id), Some(chapter_id))
+}
+
+
+#[derive(Deserialize)]
+struct EnqueueDownloadBody {
+    title_id: u32,
+    from: Option<u32>, // chapter index, inclusive
+    to: Option<u32>, // chapter index, inclusive
+}
+async fn enqueue_download_handler(AuthUser(user): AuthUser, Json(EnqueueDownloadBody { title_id, from, to }): Json<EnqueueDownloadBody>) -> Res<(StatusCode, Json<downloads::Job>)> {
+    enqueue_download(&user, title_id, from, to)
+}
+
+fn enqueue_download(user: &Account, title_id: u32, from: Option<u32>, to: Option<u32>) -> Res<(StatusCode, Json<downloads::Job>)> {
+    if !user.follows(title_id)? {
+        return Err(AppError::not_found("Title Does Not Exist"));
     }
+    let title = library::get_title(title_id)?;
+    let chapters = library::chapter_range(&title, from, to)?;
+    Ok((StatusCode::ACCEPTED, Json(downloads::enqueue(user.id, &title, chapters)?)))
+}
+
+async fn list_downloads_handler(AuthUser(user): AuthUser) -> Res<Json<Vec<downloads::Job>>> {
+    Ok(Json(downloads::list(user.id)?))
+}
+
+async fn download_handler(AuthUser(user): AuthUser, Path(job_id): Path<i64>) -> Res<Json<downloads::Job>> {
+    Ok(Json(downloads::get(user.id, job_id)?))
+}
+
+async fn cancel_download_handler(AuthUser(user): AuthUser, Path(job_id): Path<i64>) -> Res<Json<downloads::Job>> {
+    Ok(Json(downloads::cancel(user.id, job_id)?))
+}
+
+// a "progress" event with the job after every change, until it finished
+async fn download_events_handler(StreamUser(user): StreamUser, Path(job_id): Path<i64>) -> Res<Sse<impl Stream<Item = Result<SseEvent, serde_json::Error>>>> {
+    let jobs = downloads::watch(user.id, job_id)?;
+    Ok(Sse::new(jobs.map(|job| SseEvent::default().event("progress").json_data(job))).keep_alive(KeepAlive::default()))
 }
 
 
 #[derive(Deserialize)]
 struct UpdateChaptersBody {
-    username: String,
     title_id: u32,
 }
-async fn update_title_handler(Json(UpdateChaptersBody { username, title_id }): Json<UpdateChaptersBody>) -> StatusCode {
-    let Some(mut user) = User::from(&username).await else {
-        return StatusCode::NOT_FOUND;
-    };
-    let title_ref: &mut Title = user.titles.iter_mut().find(|t| t.id == title_id).unwrap();
-    
-    if let Some(()) = web::update_title(title_ref).await {
-        user.save_to_disk().await.unwrap();
+async fn update_title_handler(AuthUser(user): AuthUser, State(storage): State<Storage>, State(web): State<Fetcher>, Json(UpdateChaptersBody { title_id }): Json<UpdateChaptersBody>) -> Res<Json<chapter_diff::ChapterDiff>> {
+    if !user.follows(title_id)? {
+        return Err(AppError::not_found("Title Does Not Exist"));
+    }
+    Ok(Json(library::update_title(&storage, &web, title_id).await?))
+}
+
+
+// the suffix (percent-encoded `Chapter.s`) pins the URL to one upload of the chapter, so it can be cached for good;
+// once an update moves the chapter to another index the old URL answers 404
+async fn image_request(State(storage): State<Storage>, Path((title_id, chapter_id, suffix, image_id)): Path<(u32, u32, String, u32)>, headers: HeaderMap) -> Res<axum::response::Response> {
+    let _chapters = library::hold_chapters(title_id).await;
+    if db::store().chapter_suffix(title_id, chapter_id)?.as_deref() != Some(suffix.as_str()) {
+        return Err(AppError::not_found("Chapter Does Not Exist"));
+    }
+    let path = format!("{}/{title_id}/{chapter_id}/{image_id}.jpeg", storage.title_path());
+    let response = file_response::image_file(&headers, &path, file_response::IMMUTABLE).await?;
+    // only pages that exist, anyone can request any path here
+    retention::record_access(title_id, chapter_id);
+    Ok(response)
+}
+
+
+// number of pages of a chapter, counted now if it never was; for a reader opening the chapter
+#[derive(Serialize)]
+struct PageCount {
+    title_id: u32,
+    chapter_id: u32,
+    pages: u32,
+}
+async fn page_count_handler(AuthUser(user): AuthUser, State(storage): State<Storage>, State(web): State<Fetcher>, Path((title_id, chapter_id)): Path<(u32, u32)>) -> Res<Json<PageCount>> {
+    if !user.follows(title_id)? {
+        return Err(AppError::not_found("Title Does Not Exist"));
+    }
+    let pages = page_counts::resolve(&storage, &web, title_id, chapter_id).await?;
+    Ok(Json(PageCount { title_id, chapter_id, pages }))
+}
+
+
+async fn save_user_handler(AuthUser(account): AuthUser, State(storage): State<Storage>, Json(user): Json<user::User>) -> Res<StatusCode> {
+    // only tags and dropped titles are taken from the client, titles are followed
+    // through /new_title and progress goes through /progress
+    let current = account.user()?;
+    for title in current.titles.iter().filter(|t| !user.titles.iter().any(|kept| kept.id == t.id)) {
+        if account.unfollow(title.id)? {
+            events::send(account.id, Event::TitleRemoved { title_id: title.id });
+        }
+        library::release_title(&storage, title.id).await?;
     }
 
-    StatusCode::OK
+    // both User.tags and Title.tags describe the same links, take the union
+    let mut tags = user.tags;
+    for title in &user.titles {
+        for tag in &title.tags {
+            tags.entry(tag.clone()).or_default().push(title.id);
+        }
+    }
+    account.set_tags(&tags)?;
+    Ok(StatusCode::OK)
+}
+
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    limit: Option<usize>,
+}
+async fn feed_handler(AuthUser(account): AuthUser, Query(FeedQuery { limit }): Query<FeedQuery>) -> Res<Json<Feed>> {
+    Ok(Json(account.user()?.feed(limit)))
 }
 
 
-async fn image_request(Path((title_id, chapter_id, image_id)): Path<(u32, u32, u32)>) -> axum::http::Response<Body> {
-    if let Ok(mut file) = File::open(format!("./public/titles/{title_id}/{chapter_id}/{image_id}.jpeg")).await {
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf).await.unwrap();
+// the user's titles, narrowed by metadata: /library?genre=action,drama&status=ongoing
+async fn library_handler(AuthUser(account): AuthUser, Query(filter): Query<LibraryFilter>) -> Res<Json<Vec<Title>>> {
+    Ok(Json(account.user()?.library(&filter)))
+}
 
-        let body = Body::from(buf);
-        let response = axum::http::Response::builder()
-            .status(StatusCode::OK)
-            .header(axum::http::header::CONTENT_TYPE, "image/jpeg")
-            .body(body).unwrap();
 
-        return response;
+// live updates for the front end: new chapters, download progress, titles added or removed
+async fn events_handler(StreamUser(user): StreamUser) -> Sse<impl Stream<Item = Result<SseEvent, serde_json::Error>>> {
+    let events = events::subscribe(user.id).map(|event| SseEvent::default().event(event.name()).json_data(event));
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    from: Option<u32>, // first chapter index, inclusive
+    to: Option<u32>, // last chapter index, inclusive
+}
+
+async fn export_handler(AuthUser(user): AuthUser, State(storage): State<Storage>, Path((format, title_id)): Path<(export::Format, u32)>, Query(ExportQuery { from, to }): Query<ExportQuery>) -> Res<axum::response::Response> {
+    if !user.follows(title_id)? {
+        return Err(AppError::not_found("Title Does Not Exist"));
+    }
+    let title = library::get_title(title_id)?;
+    let chapters = library::chapter_range(&title, from, to)?;
+    // downloads only go through the queue, the client retries once the job is done
+    let missing = library::missing_chapters(&storage, &title, chapters.clone()).await;
+    if let (Some(first), Some(last)) = (missing.first(), missing.last()) {
+        let job = downloads::ensure_queued(user.id, &title, *first..=*last)?;
+        return Ok((StatusCode::CONFLICT, [(header::LOCATION, format!("/downloads/{}", job.id))], Json(job)).into_response());
     }
 
-    axum::http::Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::empty()).unwrap()
+    // written on a blocking thread straight into the response body
+    let filename = export::filename(&title, &chapters, format);
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let writer = SyncIoBridge::new(writer);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = format.write(&storage, &title, chapters, writer) {
+            println!("{format:?} export of title {} failed: {e}", title.id);
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\"")),
+        ],
+        StreamBody::new(ReaderStream::new(reader)),
+    ).into_response())
+}
+
+
+fn opds_response(kind: &'static str, xml: String) -> axum::response::Response {
+    ([(header::CONTENT_TYPE, kind)], xml).into_response()
+}
+
+async fn opds_root_handler(OpdsUser(account): OpdsUser) -> Res<axum::response::Response> {
+    Ok(opds_response(opds::NAVIGATION, opds::root(&account.user()?)))
+}
+
+#[derive(Deserialize)]
+struct OpdsTitlesQuery {
+    tag: Option<String>,
+}
+async fn opds_titles_handler(OpdsUser(account): OpdsUser, Query(OpdsTitlesQuery { tag }): Query<OpdsTitlesQuery>) -> Res<axum::response::Response> {
+    Ok(opds_response(opds::NAVIGATION, opds::titles(&account.user()?, tag.as_deref())))
+}
+
+async fn opds_title_handler(OpdsUser(account): OpdsUser, State(storage): State<Storage>, Path(title_id): Path<u32>) -> Res<axum::response::Response> {
+    let user = account.user()?;
+    let title = user.titles.iter().find(|t| t.id == title_id).ok_or_else(|| AppError::not_found("Title Does Not Exist"))?;
+    let mut downloaded = HashSet::new();
+    for chapter_id in 0..title.chapters.len() as u32 {
+        if storage.has_chapter(title_id, chapter_id).await {
+            downloaded.insert(chapter_id);
+        }
+    }
+    Ok(opds_response(opds::ACQUISITION, opds::title(title, &downloaded)))
+}
+
+
+fn default_true() -> bool { true }
+
+#[derive(Deserialize)]
+struct MarkReadBody {
+    title_id: u32,
+    chapter_id: u32,
+    #[serde(default = "default_true")]
+    read: bool, // false marks unread
+}
+async fn mark_read_handler(AuthUser(user): AuthUser, Json(MarkReadBody { title_id, chapter_id, read }): Json<MarkReadBody>) -> Res<Json<Progress>> {
+    Ok(Json(user.mark_read(title_id, chapter_id..=chapter_id, read)?))
+}
+
+
+#[derive(Deserialize)]
+struct MarkRangeBody {
+    title_id: u32,
+    from: u32, // inclusive
+    to: u32, // inclusive
+    #[serde(default = "default_true")]
+    read: bool,
+}
+async fn mark_range_handler(AuthUser(user): AuthUser, Json(MarkRangeBody { title_id, from, to, read }): Json<MarkRangeBody>) -> Res<Json<Progress>> {
+    Ok(Json(user.mark_read(title_id, from..=to, read)?))
 }
 
 
-async fn save_user_handler(Json(user): Json<user::User>) -> StatusCode {
-    user.save_to_disk().await.unwrap();
-    StatusCode::OK
-}
\ No newline at end of file
+#[derive(Deserialize)]
+struct SetPageBody {
+    title_id: u32,
+    chapter_id: u32,
+    page: u32,
+}
+async fn set_page_handler(AuthUser(user): AuthUser, Json(SetPageBody { title_id, chapter_id, page }): Json<SetPageBody>) -> Res<Json<Progress>> {
+    Ok(Json(user.set_page(title_id, chapter_id, page)?))
+}
+
+
+async fn scheduler_status_handler(_admin: AdminUser) -> Json<scheduler::Status> {
+    Json(scheduler::status().await)
+}
+
+
+async fn scheduler_run_handler(_admin: AdminUser, State(state): State<AppState>) -> StatusCode {
+    scheduler::trigger(state.config.scheduler.settings(), state.storage, state.web);
+    StatusCode::ACCEPTED
+}
+
+
+async fn retention_report_handler(_admin: AdminUser, State(config): State<Arc<Config>>, State(storage): State<Storage>) -> Res<Json<retention::Report>> {
+    Ok(Json(retention::run(&storage, &config.retention.policy(), true).await?))
+}
+
+
+async fn retention_run_handler(_admin: AdminUser, State(config): State<Arc<Config>>, State(storage): State<Storage>) -> Res<Json<retention::Report>> {
+    Ok(Json(retention::run(&storage, &config.retention.policy(), false).await?))
+}
+
+
+#[derive(Deserialize)]
+struct PinTitleBody {
+    title_id: u32,
+    pinned: bool,
+}
+async fn pin_title_handler(_admin: AdminUser, Json(PinTitleBody { title_id, pinned }): Json<PinTitleBody>) -> Res<StatusCode> {
+    db::store().set_pinned(title_id, pinned)?;
+    Ok(StatusCode::OK)
+}
+
+
+async fn cache_stats_handler(_admin: AdminUser) -> Res<Json<cache::Stats>> {
+    cache::stats().map(Json).ok_or_else(|| AppError::not_found("Image cache is disabled"))
+}
+
+
+// the effective settings after every layer was applied
+async fn config_handler(_admin: AdminUser, State(config): State<Arc<Config>>) -> Json<Config> {
+    Json(config.as_ref().clone())
+}
@@ -0,0 +1,138 @@
+use axum::async_trait;
+use reqwest::header::{HeaderMap, HeaderValue, REFERER};
+use scraper::{ElementRef, Html, Selector};
+use crate::{http, source::{self, ChapterLink, Source, TitleInfo}, timestamp, user::{TitleMeta, TitleStatus}, error::{AppError, Res}};
+
+pub struct Manganato {
+    pub referer: HeaderValue, // config http.manganato_referer
+}
+
+impl Manganato {
+    fn parse_chapters(document: &Html) -> Res<(Vec<ChapterLink>, String)> {
+        let row_selector = Selector::parse(".row-content-chapter > li").unwrap();
+        let link_selector = Selector::parse("a").unwrap();
+        let span_selector = Selector::parse("span").unwrap();
+
+        // Ex. https://manganato.com/manga-ai118410/chapter-1
+        // a row is the link, a view count and the release date
+        let mut links: Vec<ChapterLink> = document.select(&row_selector)
+            .filter_map(|row| {
+                let link = row.select(&link_selector).next()?;
+                Some(ChapterLink {
+                    text: link.text().collect::<String>(),
+                    url: link.value().attr("href")?.to_string(),
+                    released: row.select(&span_selector).nth(1).and_then(Self::release_date),
+                    group: None, // not shown
+                    language: Some("en".to_string()),
+                })
+            })
+            .collect();
+        links.reverse(); // 3,2,1 -> 1,2,3
+
+        let most_recent_date = document.select(&row_selector).next()
+            .and_then(|row| row.select(&span_selector).nth(1))
+            .ok_or_else(|| AppError::parse("missing chapter release date"))?;
+        // a date we can't read at all is still the newest chapter's, so it came out recently
+        let last_updated = Self::release_date(most_recent_date).unwrap_or_else(timestamp::get_time);
+
+        Ok((links, last_updated))
+    }
+
+    // "Oct 17,24", recent chapters say "2 hours ago" and only have "Oct 17,2024 14:05" in the title attribute
+    fn release_date(span: ElementRef) -> Option<String> {
+        timestamp::get_nelo_time(&span.text().collect::<String>()).or_else(|| {
+            let title = span.value().attr("title")?.trim();
+            timestamp::parse_date(title.rsplit_once(' ').map_or(title, |(date, _time)| date), "%b %d,%Y")
+        })
+    }
+
+    // the info table is "Alternative :", "Author(s) :", "Status :" and "Genres :" rows,
+    // any of them can be missing
+    fn parse_meta(document: &Html) -> TitleMeta {
+        let row_selector = Selector::parse(".variations-tableInfo tr").unwrap();
+        let label_selector = Selector::parse(".table-label").unwrap();
+        let value_selector = Selector::parse(".table-value").unwrap();
+        let link_selector = Selector::parse("a").unwrap();
+        let rating_selector = Selector::parse(".story-info-right-extent em[property=\"v:average\"]").unwrap();
+        let description_selector = Selector::parse("#panel-story-info-description").unwrap();
+
+        let mut meta = TitleMeta::default();
+        for row in document.select(&row_selector) {
+            let (Some(label), Some(value)) = (row.select(&label_selector).next(), row.select(&value_selector).next()) else { continue; };
+            let label = label.text().collect::<String>().to_lowercase();
+            let text = value.text().collect::<String>().trim().to_string();
+            let links = || value.select(&link_selector).map(|link| link.text().collect::<String>().trim().to_string()).filter(|name| !name.is_empty()).collect();
+            match () {
+                _ if label.contains("alternative") => meta.alt_titles = text.split(';').map(str::trim).filter(|alt| !alt.is_empty()).map(str::to_string).collect(),
+                _ if label.contains("author") => meta.authors = links(),
+                _ if label.contains("status") => meta.status = TitleStatus::parse(&text),
+                _ if label.contains("genre") => meta.genres = links(),
+                _ => {}
+            }
+        }
+        meta.rating = document.select(&rating_selector).next().and_then(|rating| rating.text().collect::<String>().trim().parse().ok());
+        meta.synopsis = document.select(&description_selector).next()
+            .map(|description| description.text().collect::<String>())
+            .map(|description| description.trim().trim_start_matches("Description :").trim().to_string())
+            .filter(|description| !description.is_empty());
+        meta
+    }
+}
+
+#[async_trait]
+impl Source for Manganato {
+    fn name(&self) -> &str {
+        "manganato"
+    }
+
+    fn handles(&self, host: &str) -> bool {
+        source::host_matches(host, &["manganato.com", "chapmanganato.com", "chapmanganato.to", "readmanganato.com"])
+    }
+
+    // pages and covers come from numbered CDN subdomains ("v13.mkklcdnv6tempv5.com")
+    fn serves_images(&self, host: &str) -> bool {
+        self.handles(host) || source::host_matches(host, &[
+            "mkklcdnv6temp.com", "mkklcdnv6tempv2.com", "mkklcdnv6tempv3.com",
+            "mkklcdnv6tempv4.com", "mkklcdnv6tempv5.com", "mkklcdnbuv1.com", "2xstorage.com",
+        ])
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(REFERER, self.referer.clone());
+        headers
+    }
+
+    async fn fetch_title(&self, client: &http::Client, url: &str) -> Res<TitleInfo> {
+        let body = http::get_text(client, url).await?;
+        let document = Html::parse_document(&body);
+
+        let title_selector = Selector::parse(".story-info-right > h1").unwrap();
+        let cover_selector = Selector::parse(".info-image > .img-loading").unwrap();
+
+        let name = document.select(&title_selector).next()
+            .ok_or_else(|| AppError::parse("missing title name"))?
+            .text().collect::<String>();
+
+        let cover_url = document.select(&cover_selector).next()
+            .and_then(|cover| cover.value().attr("src"))
+            .ok_or_else(|| AppError::parse("missing cover image"))?
+            .to_string();
+
+        let (chapters, last_updated) = Self::parse_chapters(&document)?;
+        let meta = Self::parse_meta(&document);
+
+        Ok(TitleInfo { name, cover_url, last_updated, meta, chapters })
+    }
+
+    async fn fetch_images(&self, client: &http::Client, chapter_url: &str) -> Res<Vec<String>> {
+        let body = http::get_text(client, chapter_url).await?;
+        let document = Html::parse_document(&body);
+        let selector = Selector::parse(".container-chapter-reader > img").unwrap();
+
+        Ok(document.select(&selector)
+            .filter_map(|element| element.value().attr("src"))
+            .map(str::to_string)
+            .collect())
+    }
+}
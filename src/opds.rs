@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+use chrono::Utc;
+use url::form_urlencoded;
+use crate::{
+    export::{xml_escape, Format},
+    user::{Title, User},
+};
+
+// OPDS 1.2 catalog (Atom), served under /opds for reader apps
+pub const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+pub const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+fn now() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+// titles only keep a release day
+fn updated(title: &Title) -> String {
+    match chrono::NaiveDate::parse_from_str(&title.last_updated, "%Y-%m-%d") {
+        Ok(date) => format!("{date}T00:00:00Z"),
+        Err(_) => now(),
+    }
+}
+
+fn tag_href(tag: &str) -> String {
+    let tag: String = form_urlencoded::byte_serialize(tag.as_bytes()).collect();
+    format!("/opds/titles?tag={tag}")
+}
+
+fn feed(id: &str, title: &str, self_href: &str, kind: &str, links: &str, entries: &str) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml += "<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:opds=\"http://opds-spec.org/2010/catalog\">\n";
+    xml += &format!("  <id>urn:md_api:{}</id>\n", xml_escape(id));
+    xml += &format!("  <title>{}</title>\n", xml_escape(title));
+    xml += &format!("  <updated>{}</updated>\n", now());
+    xml += "  <author><name>md_api</name></author>\n";
+    xml += &format!("  <link rel=\"self\" href=\"{}\" type=\"{kind}\"/>\n", xml_escape(self_href));
+    xml += &format!("  <link rel=\"start\" href=\"/opds\" type=\"{NAVIGATION}\"/>\n");
+    xml += links;
+    xml += entries;
+    xml += "</feed>\n";
+    xml
+}
+
+fn navigation_entry(id: &str, title: &str, href: &str, kind: &str, content: &str) -> String {
+    format!(
+        "  <entry>\n    <id>urn:md_api:{}</id>\n    <title>{}</title>\n    <updated>{}</updated>\n    <content type=\"text\">{}</content>\n    <link rel=\"subsection\" href=\"{}\" type=\"{kind}\"/>\n  </entry>\n",
+        xml_escape(id), xml_escape(title), now(), xml_escape(content), xml_escape(href),
+    )
+}
+
+/// Start page: every title, then one entry per tag
+pub fn root(user: &User) -> String {
+    let mut entries = navigation_entry("titles", "All Titles", "/opds/titles", NAVIGATION, &format!("{} titles", user.titles.len()));
+    let mut tags: Vec<_> = user.tags.iter().collect();
+    tags.sort_by_key(|(tag, _)| tag.to_lowercase());
+    for (tag, ids) in tags {
+        entries += &navigation_entry(&format!("tag:{tag}"), tag, &tag_href(tag), NAVIGATION, &format!("{} titles", ids.len()));
+    }
+    feed(&format!("user:{}", user.id), &format!("{}'s Library", user.username), "/opds", NAVIGATION, "", &entries)
+}
+
+/// Followed titles, optionally only those with `tag`. Tags are offered as facets.
+pub fn titles(user: &User, tag: Option<&str>) -> String {
+    let mut links = String::new();
+    let mut tags: Vec<&String> = user.tags.keys().collect();
+    tags.sort_by_key(|tag| tag.to_lowercase());
+    for name in tags {
+        let active = if Some(name.as_str()) == tag { " opds:activeFacet=\"true\"" } else { "" };
+        links += &format!(
+            "  <link rel=\"http://opds-spec.org/facet\" href=\"{}\" title=\"{}\" opds:facetGroup=\"Tags\"{active}/>\n",
+            xml_escape(&tag_href(name)), xml_escape(name),
+        );
+    }
+
+    let mut titles: Vec<&Title> = user.titles.iter()
+        .filter(|title| tag.is_none_or(|tag| user.tags.get(tag).is_some_and(|ids| ids.contains(&title.id))))
+        .collect();
+    titles.sort_by(|a, b| b.last_updated.cmp(&a.last_updated));
+
+    let mut entries = String::new();
+    for title in titles {
+        let unread = title.unread_chapters().count();
+        entries += &format!(
+            "  <entry>\n    <id>urn:md_api:title:{id}</id>\n    <title>{}</title>\n    <updated>{}</updated>\n    <content type=\"text\">{} chapters, {unread} unread</content>\n",
+            xml_escape(&title.name), updated(title), title.chapters.len(), id = title.id,
+        );
+        for category in &title.tags {
+            entries += &format!("    <category term=\"{0}\" label=\"{0}\"/>\n", xml_escape(category));
+        }
+        entries += &format!("    <link rel=\"http://opds-spec.org/image\" href=\"/cover/{}\" type=\"image/jpeg\"/>\n", title.id);
+        entries += &format!("    <link rel=\"http://opds-spec.org/image/thumbnail\" href=\"/cover/{}\" type=\"image/jpeg\"/>\n", title.id);
+        entries += &format!("    <link rel=\"subsection\" href=\"/opds/titles/{}\" type=\"{ACQUISITION}\"/>\n  </entry>\n", title.id);
+    }
+
+    let (id, name, href) = match tag {
+        Some(tag) => (format!("user:{}:tag:{tag}", user.id), tag.to_string(), tag_href(tag)),
+        None => (format!("user:{}:titles", user.id), "All Titles".to_string(), "/opds/titles".to_string()),
+    };
+    feed(&id, &name, &href, NAVIGATION, &links, &entries)
+}
+
+fn acquisition_links(title_id: u32, query: &str) -> String {
+    [Format::Cbz, Format::Epub, Format::Pdf].into_iter()
+        .map(|format| format!(
+            "    <link rel=\"http://opds-spec.org/acquisition\" href=\"/export/{}/{title_id}{}\" type=\"{}\"/>\n",
+            format.extension(), xml_escape(query), format.content_type(),
+        ))
+        .collect()
+}
+
+/// Chapters of one title, each downloadable as CBZ (or EPUB/PDF), plus the whole title.
+/// Exports only stream what is on disk, so chapters missing from `downloaded` get no acquisition link.
+pub fn title(title: &Title, downloaded: &HashSet<u32>) -> String {
+    let updated = updated(title);
+    let cover = format!(
+        "    <link rel=\"http://opds-spec.org/image\" href=\"/cover/{0}\" type=\"image/jpeg\"/>\n    <link rel=\"http://opds-spec.org/image/thumbnail\" href=\"/cover/{0}\" type=\"image/jpeg\"/>\n",
+        title.id,
+    );
+
+    let mut entries = String::new();
+    if !title.chapters.is_empty() && (0..title.chapters.len() as u32).all(|chapter_id| downloaded.contains(&chapter_id)) {
+        entries += &format!(
+            "  <entry>\n    <id>urn:md_api:title:{}:all</id>\n    <title>{} (Complete)</title>\n    <updated>{updated}</updated>\n{cover}{}  </entry>\n",
+            title.id, xml_escape(&title.name), acquisition_links(title.id, ""),
+        );
+    }
+    for (chapter_id, chapter) in (0u32..).zip(&title.chapters) {
+        let name = if chapter.t.trim().is_empty() { format!("Chapter {}", chapter_id + 1) } else { chapter.t.clone() };
+        let status = if title.read.contains(&chapter_id) { "read" } else { "unread" };
+        let (status, links) = match downloaded.contains(&chapter_id) {
+            true => (status.to_string(), acquisition_links(title.id, &format!("?from={chapter_id}&to={chapter_id}"))),
+            false => (format!("{status}, not downloaded"), String::new()),
+        };
+        entries += &format!(
+            "  <entry>\n    <id>urn:md_api:title:{}:chapter:{chapter_id}</id>\n    <title>{}</title>\n    <updated>{updated}</updated>\n    <content type=\"text\">{status}</content>\n{cover}{links}  </entry>\n",
+            title.id, xml_escape(&name),
+        );
+    }
+
+    let links = format!("  <link rel=\"up\" href=\"/opds/titles\" type=\"{NAVIGATION}\"/>\n");
+    feed(&format!("title:{}", title.id), &title.name, &format!("/opds/titles/{}", title.id), ACQUISITION, &links, &entries)
+}
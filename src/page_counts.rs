@@ -0,0 +1,89 @@
+use std::{sync::OnceLock, time::Duration};
+use tokio::sync::{Notify, Semaphore};
+use crate::{db, error::{AppError, Res}, library, shutdown, storage::Storage, web::Fetcher};
+
+// chapters whose count failed are tried again this long after
+const RETRY_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Page counts (`Chapter.i`, known once `Chapter.counted`) are scraped when a chapter is opened,
+/// or by a background pass over the catalog
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub concurrency: usize, // chapter pages scraped at the same time, process wide
+    pub delay: Duration, // between requests of the background pass
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { concurrency: 4, delay: Duration::from_millis(500) }
+    }
+}
+
+// every page count request waits for a permit, on demand or in the background
+static POOL: OnceLock<Semaphore> = OnceLock::new();
+// titles were added or gained chapters
+static WAKE: Notify = Notify::const_new();
+
+/// Sizes the pool and starts the background pass
+pub fn start(settings: Settings, storage: Storage, web: Fetcher) {
+    POOL.set(Semaphore::new(settings.concurrency.max(1))).ok();
+    shutdown::spawn(async move {
+        loop {
+            shutdown::unless_stopping(count_missing(&storage, &web, settings.delay)).await;
+            let next_pass = async {
+                tokio::select! {
+                    _ = WAKE.notified() => {}
+                    _ = tokio::time::sleep(RETRY_AFTER) => {}
+                }
+            };
+            if shutdown::unless_stopping(next_pass).await.is_none() {
+                break;
+            }
+        }
+    });
+}
+
+/// New chapters to count, the background pass picks them up
+pub fn wake() {
+    WAKE.notify_one();
+}
+
+/// Pages of a chapter, scraped (and saved) if nobody counted them yet
+pub async fn resolve(storage: &Storage, web: &Fetcher, title_id: u32, chapter_id: u32) -> Res<u32> {
+    let title = library::get_title(title_id)?;
+    let chapter = title.chapters.get(chapter_id as usize)
+        .ok_or_else(|| AppError::not_found("Chapter Does Not Exist"))?;
+    if chapter.counted {
+        return Ok(chapter.i);
+    }
+    count(storage, web, title_id, chapter_id, &chapter.s, &format!("{}{}", title.chap_prefix, chapter.s)).await
+}
+
+// downloaded chapters are counted on disk, the others on the site
+async fn count(storage: &Storage, web: &Fetcher, title_id: u32, chapter_id: u32, suffix: &str, url: &str) -> Res<u32> {
+    let pages = match storage.get_num_images(title_id, chapter_id).await {
+        Ok(pages) if pages > 0 => pages,
+        _ => {
+            let _permit = POOL.get().expect("page_counts::start not called").acquire().await
+                .map_err(|_| AppError::Unavailable("page counts stopped".to_string()))?;
+            web.count_pages(url).await?
+        }
+    };
+    // the suffix guards against an update moving the chapter in the meantime
+    db::store().set_page_count(title_id, chapter_id, suffix, pages)?;
+    Ok(pages)
+}
+
+// one chapter at a time, so readers opening chapters always find a free permit
+async fn count_missing(storage: &Storage, web: &Fetcher, delay: Duration) {
+    let chapters = match db::store().uncounted_chapters() {
+        Ok(chapters) => chapters,
+        Err(e) => return println!("Could not list chapters to count: {e}"),
+    };
+    for (title_id, chapter_id, suffix, url) in chapters {
+        if let Err(e) = count(storage, web, title_id, chapter_id, &suffix, &url).await {
+            println!("Could not count pages of {title_id}/{chapter_id}: {e}");
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
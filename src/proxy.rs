@@ -0,0 +1,191 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use axum::body::Bytes;
+use reqwest::{header::USER_AGENT, redirect, Client, Url};
+use crate::{
+    config,
+    error::{AppError, Res},
+    file_response::sniff_image_type,
+    http,
+    source::{self, Source},
+    web,
+};
+
+pub struct ProxiedImage {
+    pub content_type: String,
+    pub bytes: Bytes,
+}
+
+/// Fetches an image for the client, only from hosts a registered source serves images from.
+/// The host is resolved once and the connection pinned to the checked addresses.
+pub async fn fetch_image(web: &web::Fetcher, url: &str, limits: &config::Proxy) -> Res<ProxiedImage> {
+    let max_bytes = limits.max_image_bytes();
+    let url = Url::parse(url).map_err(|e| AppError::BadRequest(format!("invalid url: {e}")))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::BadRequest(format!("unsupported scheme {}", url.scheme())));
+    }
+    let host = url.host_str().ok_or_else(|| AppError::BadRequest("url has no host".to_string()))?.to_string();
+    let source = source::for_image_url(&url)
+        .ok_or_else(|| AppError::BadRequest(format!("{host} is not an image host of any source")))?;
+    let addrs = resolve_public(&host, url.port_or_known_default().unwrap_or(443)).await?;
+
+    // retried like every source request, each attempt capped at `limits.timeout_secs`
+    let client = web.wrap(pinned_client(web, source.as_ref(), &host, &addrs, limits)?);
+    let http::Fetched { content_type, bytes: body } = http::get(&client, url.as_str(), Some(max_bytes)).await?;
+    let upstream_type = content_type.filter(|value| value.starts_with("image/"));
+
+    // some CDNs send octet-stream, trust the bytes over a missing header
+    let content_type = match upstream_type {
+        Some(content_type) => content_type,
+        None => match sniff_image_type(&body) {
+            "application/octet-stream" => return Err(AppError::Network(format!("{host} did not return an image"))),
+            sniffed => sniffed.to_string(),
+        },
+    };
+    Ok(ProxiedImage { content_type, bytes: body })
+}
+
+// no redirects, a redirect could point anywhere
+fn pinned_client(web: &web::Fetcher, source: &dyn Source, host: &str, addrs: &[SocketAddr], limits: &config::Proxy) -> Res<Client> {
+    let mut headers = source.headers();
+    headers.insert(USER_AGENT, web.user_agent());
+    Ok(Client::builder()
+        .default_headers(headers)
+        .resolve_to_addrs(host, addrs)
+        .redirect(redirect::Policy::none())
+        .timeout(Duration::from_secs(limits.timeout_secs))
+        .connect_timeout(Duration::from_secs(limits.connect_timeout_secs))
+        .build()?)
+}
+
+/// Resolves `host` and refuses it if any address is internal
+async fn resolve_public(host: &str, port: u16) -> Res<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(['[', ']']), port)).await
+        .map_err(|e| AppError::Network(format!("could not resolve {host}: {e}")))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(AppError::Network(format!("could not resolve {host}")));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(AppError::BadRequest(format!("{host} resolves to a private address ({})", addr.ip())));
+    }
+    Ok(addrs)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+// v6 addresses that reach a v4 host, which is what has to be public
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [a, b, c, d, e, f, g, h] = ip.segments();
+    let v4 = |high: u16, low: u16| Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)));
+    match (a, b, c, d, e, f) {
+        (0, 0, 0, 0, 0, 0xffff) => v4(g, h), // mapped
+        (0, 0, 0, 0, 0, 0) => v4(g, h), // compatible (also :: and ::1, refused as 0.0.0.x)
+        (0x64, 0xff9b, 0, 0, 0, 0) => v4(g, h), // NAT64
+        (0x2002, ..) => v4(b, c), // 6to4
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0 // "this network"
+        || (a == 100 && (64..128).contains(&b)) // carrier-grade NAT
+        || (a == 198 && (b == 18 || b == 19)) // benchmarking
+        || a >= 240) // reserved
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00 // unique local
+        || (first & 0xffc0) == 0xfe80 // link local
+        || first == 0x2001 && ip.segments()[1] == 0x0db8) // documentation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn private_and_loopback_v4_are_refused() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1", "198.18.0.1", "255.255.255.255", "224.0.0.1", "240.0.0.1"] {
+            assert!(!public(ip), "{ip} should be refused");
+        }
+    }
+
+    #[test]
+    fn public_v4_is_allowed() {
+        for ip in ["1.1.1.1", "8.8.8.8", "172.32.0.1", "100.128.0.1", "203.0.114.1"] {
+            assert!(public(ip), "{ip} should be allowed");
+        }
+    }
+
+    #[test]
+    fn private_and_loopback_v6_are_refused() {
+        for ip in ["::1", "::", "fc00::1", "fd12:3456::1", "fe80::1", "ff02::1", "2001:db8::1"] {
+            assert!(!public(ip), "{ip} should be refused");
+        }
+    }
+
+    #[test]
+    fn mapped_v4_is_checked_as_v4() {
+        assert!(!public("::ffff:127.0.0.1"));
+        assert!(!public("::ffff:10.0.0.1"));
+        assert!(!public("::ffff:169.254.169.254"));
+        assert!(public("::ffff:8.8.8.8"));
+    }
+
+    #[test]
+    fn embedded_v4_is_checked_as_v4() {
+        // NAT64
+        assert!(!public("64:ff9b::127.0.0.1"));
+        assert!(!public("64:ff9b::a00:1"));
+        assert!(public("64:ff9b::8.8.8.8"));
+        // 6to4
+        assert!(!public("2002:7f00:1::"));
+        assert!(!public("2002:c0a8:101::1"));
+        assert!(public("2002:808:808::1"));
+        // compatible
+        assert!(!public("::127.0.0.1"));
+        assert!(!public("::10.0.0.1"));
+        assert!(public("::8.8.8.8"));
+    }
+
+    #[test]
+    fn public_v6_is_allowed() {
+        assert!(public("2606:4700:4700::1111"));
+        assert!(public("2a00:1450:4001::1"));
+    }
+
+    #[tokio::test]
+    async fn loopback_host_is_refused_before_connecting() {
+        let error = resolve_public("127.0.0.1", 80).await.unwrap_err();
+        assert!(matches!(error, AppError::BadRequest(_)));
+        let error = resolve_public("[::1]", 80).await.unwrap_err();
+        assert!(matches!(error, AppError::BadRequest(_)));
+    }
+}
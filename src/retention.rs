@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::{db, error::Res, shutdown, storage::Storage};
+
+/// What stays on disk under the titles folder
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub budget: u64, // bytes for every downloaded chapter together
+    pub keep_ahead: u32, // chapters kept after each reader's last_chap
+    pub grace: Duration, // never evict chapters used this recently
+    pub interval: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            budget: 2 * 1024 * 1024 * 1024, // 2GB
+            keep_ahead: 3,
+            grace: Duration::from_secs(60 * 10), // 10m
+            interval: Duration::from_secs(60 * 30), // 30m
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Eviction {
+    pub title_id: u32,
+    pub chapter_id: Option<u32>, // None for a whole title
+    pub bytes: u64,
+    pub last_access: DateTime<Utc>,
+    pub reason: &'static str, // "orphaned" or "lru"
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub dry_run: bool,
+    pub budget: u64,
+    pub used_bytes: u64,
+    pub protected_bytes: u64, // pinned, ahead of a reader or inside the grace period
+    pub freed_bytes: u64,
+    pub evictions: Vec<Eviction>,
+    pub errors: Vec<String>,
+}
+
+struct OnDisk {
+    title_id: u32,
+    chapter_id: u32,
+    bytes: u64,
+    last_access: SystemTime,
+}
+
+// page views since startup, the folder's mtime (download time) is used otherwise
+static LAST_ACCESS: Mutex<Option<HashMap<(u32, u32), SystemTime>>> = Mutex::new(None);
+
+pub fn record_access(title_id: u32, chapter_id: u32) {
+    LAST_ACCESS.lock().unwrap().get_or_insert_with(HashMap::new).insert((title_id, chapter_id), SystemTime::now());
+}
+
+fn last_access(title_id: u32, chapter_id: u32) -> Option<SystemTime> {
+    LAST_ACCESS.lock().unwrap().as_ref()?.get(&(title_id, chapter_id)).copied()
+}
+
+// chapters that left the disk since they were read
+fn forget_access(except: &HashSet<(u32, u32)>) {
+    if let Some(accesses) = LAST_ACCESS.lock().unwrap().as_mut() {
+        accesses.retain(|chapter, _| except.contains(chapter));
+    }
+}
+
+/// Applies the policy now and then every `policy.interval`.
+/// A pass that already started deleting finishes before shutdown.
+pub fn start(policy: Policy, storage: Storage) {
+    shutdown::spawn(async move {
+        loop {
+            match run(&storage, &policy, false).await {
+                Ok(report) if !report.evictions.is_empty() || !report.errors.is_empty() => println!(
+                    "Retention freed {} bytes ({} evictions, {} errors)",
+                    report.freed_bytes, report.evictions.len(), report.errors.len(),
+                ),
+                Ok(_) => {}
+                Err(e) => println!("Retention run failed: {e}"),
+            }
+            if shutdown::unless_stopping(tokio::time::sleep(policy.interval)).await.is_none() {
+                break;
+            }
+        }
+    });
+}
+
+/// Evicts (or with `dry_run` only lists) what the policy doesn't keep.
+/// Least recently used chapters go first until everything fits the budget.
+pub async fn run(storage: &Storage, policy: &Policy, dry_run: bool) -> Res<Report> {
+    let catalog: HashSet<u32> = db::store().catalog_ids()?.into_iter().collect();
+    let pinned: HashSet<u32> = db::store().pinned_title_ids()?.into_iter().collect();
+    let mut keep: HashSet<(u32, u32)> = HashSet::new();
+    for (title_id, last_chap) in db::store().reading_positions()? {
+        keep.extend((last_chap..=last_chap.saturating_add(policy.keep_ahead)).map(|chapter_id| (title_id, chapter_id)));
+    }
+
+    let titles = storage.title_path().to_string();
+    let (chapters, mut errors) = tokio::task::spawn_blocking(move || scan(Path::new(&titles))).await?;
+    let now = SystemTime::now();
+    let used_bytes = chapters.iter().map(|chapter| chapter.bytes).sum();
+    let mut on_disk: HashSet<(u32, u32)> = chapters.iter().map(|chapter| (chapter.title_id, chapter.chapter_id)).collect();
+
+    let mut evictions = Vec::new();
+    let mut orphans: HashMap<u32, Eviction> = HashMap::new();
+    let mut candidates = Vec::new();
+    let mut protected_bytes = 0;
+    for chapter in chapters {
+        let recent = now.duration_since(chapter.last_access).unwrap_or_default() < policy.grace;
+        if !catalog.contains(&chapter.title_id) && !recent {
+            let orphan = orphans.entry(chapter.title_id).or_insert(Eviction {
+                title_id: chapter.title_id,
+                chapter_id: None,
+                bytes: 0,
+                last_access: chapter.last_access.into(),
+                reason: "orphaned",
+            });
+            orphan.bytes += chapter.bytes;
+            orphan.last_access = orphan.last_access.max(chapter.last_access.into());
+        } else if recent || pinned.contains(&chapter.title_id) || keep.contains(&(chapter.title_id, chapter.chapter_id)) {
+            protected_bytes += chapter.bytes;
+        } else {
+            candidates.push(chapter);
+        }
+    }
+    let mut remaining: u64 = used_bytes - orphans.values().map(|orphan| orphan.bytes).sum::<u64>();
+    evictions.extend(orphans.into_values());
+
+    candidates.sort_by_key(|chapter| chapter.last_access);
+    for chapter in candidates {
+        if remaining <= policy.budget {
+            break;
+        }
+        remaining -= chapter.bytes;
+        evictions.push(Eviction {
+            title_id: chapter.title_id,
+            chapter_id: Some(chapter.chapter_id),
+            bytes: chapter.bytes,
+            last_access: chapter.last_access.into(),
+            reason: "lru",
+        });
+    }
+
+    let mut freed_bytes = 0;
+    if !dry_run {
+        for eviction in &evictions {
+            let result = match eviction.chapter_id {
+                Some(chapter_id) => storage.delete_chapter(&eviction.title_id, &chapter_id).await,
+                None => storage.remove_title(&eviction.title_id).await,
+            };
+            match result {
+                Ok(()) => {
+                    freed_bytes += eviction.bytes;
+                    on_disk.retain(|(title_id, chapter_id)| *title_id != eviction.title_id || eviction.chapter_id.is_some_and(|id| id != *chapter_id));
+                }
+                Err(e) => errors.push(format!("{}/{}: {e}", eviction.title_id, eviction.chapter_id.map_or("*".to_string(), |id| id.to_string()))),
+            }
+        }
+    }
+    forget_access(&on_disk);
+
+    Ok(Report { dry_run, budget: policy.budget, used_bytes, protected_bytes, freed_bytes, evictions, errors })
+}
+
+// every "{title}/{chapter}" folder with its size, unreadable entries are reported and skipped
+fn scan(titles: &Path) -> (Vec<OnDisk>, Vec<String>) {
+    let (mut chapters, mut errors) = (Vec::new(), Vec::new());
+    let title_dirs = match std::fs::read_dir(titles) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return (chapters, errors),
+        Err(e) => return (chapters, vec![format!("{}: {e}", titles.display())]),
+    };
+    for title_dir in title_dirs.filter_map(|entry| entry.ok()) {
+        let Some(title_id) = title_dir.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue; };
+        let chapter_dirs = match std::fs::read_dir(title_dir.path()) {
+            Ok(dirs) => dirs,
+            Err(e) => { errors.push(format!("{}: {e}", title_dir.path().display())); continue; }
+        };
+        for chapter_dir in chapter_dirs.filter_map(|entry| entry.ok()) {
+            let Some(chapter_id) = chapter_dir.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue; };
+            match chapter_size(&chapter_dir.path()) {
+                Ok((bytes, modified)) => chapters.push(OnDisk {
+                    title_id,
+                    chapter_id,
+                    bytes,
+                    last_access: last_access(title_id, chapter_id).unwrap_or(modified).max(modified),
+                }),
+                Err(e) => errors.push(format!("{}: {e}", chapter_dir.path().display())),
+            }
+        }
+    }
+    (chapters, errors)
+}
+
+fn chapter_size(dir: &Path) -> std::io::Result<(u64, SystemTime)> {
+    let modified = std::fs::metadata(dir)?.modified()?;
+    let mut bytes = 0;
+    for page in std::fs::read_dir(dir)? {
+        bytes += page?.metadata()?.len();
+    }
+    Ok((bytes, modified))
+}
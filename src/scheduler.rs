@@ -0,0 +1,156 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use reqwest::Url;
+use serde::Serialize;
+use tokio::sync::{Mutex, RwLock};
+use crate::{db, error::Res, latency::Latency, library, shutdown, storage::Storage, web::Fetcher};
+
+/// How the background refresh of followed titles behaves
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub interval: Duration,
+    pub concurrency: usize,
+    pub per_host_delay: Duration, // between title page requests to the same site
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            interval: Duration::from_secs(60 * 60 * 6), // 6h
+            concurrency: 4,
+            per_host_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct Status {
+    pub running: bool,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub titles_checked: u32,
+    pub titles_updated: u32,
+    pub errors: Vec<String>, // "{title_id}: {error}" from the last run
+}
+
+static STATUS: RwLock<Status> = RwLock::const_new(Status {
+    running: false,
+    last_started: None,
+    last_finished: None,
+    next_run: None,
+    titles_checked: 0,
+    titles_updated: 0,
+    errors: Vec::new(),
+});
+
+pub async fn status() -> Status {
+    STATUS.read().await.clone()
+}
+
+/// Refreshes every followed title now and then every `settings.interval`.
+/// Shutdown interrupts a pass, titles not reached yet are simply checked next time.
+pub fn start(settings: Settings, storage: Storage, web: Fetcher) {
+    shutdown::spawn(async move {
+        loop {
+            match shutdown::unless_stopping(run_once(&settings, &storage, &web)).await {
+                Some(Err(e)) => println!("Scheduled refresh failed: {e}"),
+                Some(Ok(())) => {}
+                None => break,
+            }
+            let next_run = Utc::now() + chrono::Duration::from_std(settings.interval).unwrap_or(chrono::Duration::zero());
+            STATUS.write().await.next_run = Some(next_run);
+            if shutdown::unless_stopping(tokio::time::sleep(settings.interval)).await.is_none() {
+                break;
+            }
+        }
+    });
+}
+
+/// Starts an extra pass in the background (admin endpoint)
+pub fn trigger(settings: Settings, storage: Storage, web: Fetcher) {
+    shutdown::spawn(async move {
+        if let Some(Err(e)) = shutdown::unless_stopping(run_once(&settings, &storage, &web)).await {
+            println!("Manual refresh failed: {e}");
+        }
+    });
+}
+
+/// One pass over the catalog. Returns immediately if a pass is already running.
+pub async fn run_once(settings: &Settings, storage: &Storage, web: &Fetcher) -> Res<()> {
+    {
+        let mut status = STATUS.write().await;
+        if status.running {
+            return Ok(());
+        }
+        status.running = true;
+        status.last_started = Some(Utc::now());
+        status.titles_checked = 0;
+        status.titles_updated = 0;
+        status.errors.clear();
+    }
+    let mut timer = Latency::new("scheduler");
+
+    let result = refresh_all(settings, storage, web).await;
+
+    let mut status = STATUS.write().await;
+    status.running = false;
+    status.last_finished = Some(Utc::now());
+    timer.tick(&format!("checked {} titles, {} updated", status.titles_checked, status.titles_updated));
+    result
+}
+
+async fn refresh_all(settings: &Settings, storage: &Storage, web: &Fetcher) -> Res<()> {
+    let ids = db::store().followed_title_ids()?;
+    let limiter = HostLimiter::new(settings.per_host_delay);
+
+    stream::iter(ids)
+        .for_each_concurrent(settings.concurrency.max(1), |id| {
+            let limiter = &limiter;
+            async move {
+                let result = refresh_one(storage, web, limiter, id).await;
+                let mut status = STATUS.write().await;
+                status.titles_checked += 1;
+                match result {
+                    Ok(true) => status.titles_updated += 1,
+                    Ok(false) => {}
+                    Err(e) => status.errors.push(format!("{id}: {e}")),
+                }
+            }
+        })
+        .await;
+    Ok(())
+}
+
+async fn refresh_one(storage: &Storage, web: &Fetcher, limiter: &HostLimiter, id: u32) -> Res<bool> {
+    let title = library::get_title(id)?;
+    let host = Url::parse(&title.url).ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    limiter.wait(&host).await;
+    Ok(!library::update_title(storage, web, id).await?.is_empty())
+}
+
+/// Spaces out requests to the same host, different hosts don't wait on each other
+struct HostLimiter {
+    delay: Duration,
+    next_slot: Mutex<HashMap<String, Instant>>,
+}
+
+impl HostLimiter {
+    fn new(delay: Duration) -> HostLimiter {
+        HostLimiter { delay, next_slot: Mutex::new(HashMap::new()) }
+    }
+
+    async fn wait(&self, host: &str) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let now = Instant::now();
+            let slot = next_slot.get(host).copied().filter(|slot| *slot > now).unwrap_or(now);
+            next_slot.insert(host.to_string(), slot + self.delay);
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+}
@@ -0,0 +1,225 @@
+use std::path::Path;
+use axum::async_trait;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, REFERER},
+    Url,
+};
+use scraper::{ElementRef, Html, Selector};
+use serde::Deserialize;
+use crate::{http, source::{self, ChapterLink, Source, TitleInfo}, timestamp, user::{TitleMeta, TitleStatus}, error::{AppError, Res}};
+
+/// A site described entirely by CSS selectors, loaded from a .toml or .json file.
+/// ```toml
+/// name = "mangakakalot"
+/// hosts = ["mangakakalot.com"]
+/// referer = "https://mangakakalot.com/"
+/// image_hosts = ["mkklcdnv6temp.com"]
+/// title_selector = ".manga-info-text > li > h1"
+/// cover_selector = ".manga-info-pic > img"
+/// chapter_selector = ".chapter-list > .row > span > a"
+/// date_selector = ".chapter-list > .row > span:last-child"
+/// date_format = "%b-%d-%y"
+/// group_selector = ".chapter-list > .row > .group"
+/// language = "en"
+/// image_selector = ".container-chapter-reader > img"
+/// author_selector = ".manga-info-text a[href*='author']"
+/// genre_selector = ".manga-info-text a[href*='genre']"
+/// status_selector = ".manga-info-text > li:nth-child(3)"
+/// synopsis_selector = "#noidungm"
+/// ```
+/// When `date_selector` (or `group_selector`) matches once per chapter, each chapter gets its own value.
+/// Metadata selectors are optional, list ones (authors, artists, genres, alt titles) take every match.
+#[derive(Deserialize, Debug)]
+pub struct SelectorSource {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub referer: Option<String>,
+    #[serde(default)]
+    pub image_hosts: Vec<String>, // CDNs serving pages and covers, besides `hosts`
+    pub title_selector: String,
+    pub cover_selector: String,
+    #[serde(default = "default_src_attr")]
+    pub cover_attr: String,
+    pub chapter_selector: String,
+    #[serde(default = "default_true")]
+    pub chapters_newest_first: bool,
+    pub date_selector: String,
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
+    pub group_selector: Option<String>,
+    pub language: Option<String>, // of every chapter on the site
+    pub image_selector: String,
+    #[serde(default = "default_src_attr")]
+    pub image_attr: String,
+    pub author_selector: Option<String>,
+    pub artist_selector: Option<String>,
+    pub genre_selector: Option<String>,
+    pub status_selector: Option<String>,
+    pub synopsis_selector: Option<String>,
+    pub alt_titles_selector: Option<String>,
+    pub rating_selector: Option<String>,
+    #[serde(default = "default_rating_scale")]
+    pub rating_scale: f64, // best possible rating on the site, stored out of 5
+}
+
+fn default_src_attr() -> String { "src".to_string() }
+fn default_date_format() -> String { "%b %d,%y".to_string() }
+fn default_true() -> bool { true }
+fn default_rating_scale() -> f64 { 5.0 }
+
+impl SelectorSource {
+    pub async fn from_file(path: &Path) -> Res<SelectorSource> {
+        let content = tokio::fs::read_to_string(path).await?;
+        let source: SelectorSource = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| AppError::Parse(e.to_string()))?,
+            Some("json") => serde_json::from_str(&content).map_err(|e| AppError::Parse(e.to_string()))?,
+            _ => return Err(AppError::Parse(format!("unknown source definition format: {}", path.display()))),
+        };
+        source.validate()?;
+        Ok(source)
+    }
+
+    // catch typos at startup rather than on the first request
+    fn validate(&self) -> Res<()> {
+        if self.hosts.is_empty() {
+            return Err(AppError::Parse(format!("source {} has no hosts", self.name)));
+        }
+        let selectors = [&self.title_selector, &self.cover_selector, &self.chapter_selector, &self.date_selector, &self.image_selector];
+        let optional = [
+            &self.group_selector, &self.author_selector, &self.artist_selector, &self.genre_selector,
+            &self.status_selector, &self.synopsis_selector, &self.alt_titles_selector, &self.rating_selector,
+        ];
+        for selector in selectors.into_iter().chain(optional.into_iter().flatten()) {
+            Selector::parse(selector).map_err(|e| AppError::Parse(format!("source {}: bad selector {selector:?}: {e:?}", self.name)))?;
+        }
+        if self.rating_scale <= 0.0 {
+            return Err(AppError::Parse(format!("source {}: rating_scale must be positive", self.name)));
+        }
+        if let Some(referer) = &self.referer {
+            HeaderValue::from_str(referer).map_err(|e| AppError::Parse(format!("source {}: bad referer: {e}", self.name)))?;
+        }
+        Ok(())
+    }
+
+    fn select<'a>(document: &'a Html, selector: &str) -> impl Iterator<Item = ElementRef<'a>> {
+        // selectors were checked in validate()
+        let selector = Selector::parse(selector).unwrap();
+        document.select(&selector).collect::<Vec<_>>().into_iter()
+    }
+
+    fn parse_chapters(&self, document: &Html, page_url: &Url) -> (Vec<ChapterLink>, String) {
+        let anchors: Vec<ElementRef> = Self::select(document, &self.chapter_selector).collect();
+        // only trusted as per-chapter values if there is exactly one per chapter
+        let per_chapter = |selector: &str, read: &dyn Fn(String) -> Option<String>| -> Vec<Option<String>> {
+            let values: Vec<Option<String>> = Self::select(document, selector).map(|element| read(element.text().collect())).collect();
+            if values.len() == anchors.len() { values } else { vec![None; anchors.len()] }
+        };
+        let dates = per_chapter(&self.date_selector, &|date| timestamp::parse_date(&date, &self.date_format));
+        let groups = match &self.group_selector {
+            Some(selector) => per_chapter(selector, &|group| Some(group.trim().to_string()).filter(|group| !group.is_empty())),
+            None => vec![None; anchors.len()],
+        };
+
+        let mut links: Vec<ChapterLink> = anchors.iter().zip(dates).zip(groups)
+            .filter_map(|((link, released), group)| Some(ChapterLink {
+                text: link.text().collect::<String>().trim().to_string(),
+                url: page_url.join(link.value().attr("href")?).ok()?.to_string(),
+                released,
+                group,
+                language: self.language.clone(),
+            }))
+            .collect();
+        if self.chapters_newest_first {
+            links.reverse();
+        }
+
+        // the newest date wherever it is listed, "%Y-%m-%d" sorts as text
+        let last_updated = Self::select(document, &self.date_selector)
+            .filter_map(|date| timestamp::parse_date(&date.text().collect::<String>(), &self.date_format))
+            .max()
+            .unwrap_or_else(|| {
+                println!("{}: could not read release date, using today", self.name);
+                timestamp::get_time()
+            });
+
+        (links, last_updated)
+    }
+
+    fn parse_meta(&self, document: &Html) -> TitleMeta {
+        let texts = |selector: &Option<String>| -> Vec<String> {
+            let Some(selector) = selector else { return Vec::new(); };
+            Self::select(document, selector)
+                .map(|element| element.text().collect::<String>().trim().to_string())
+                .filter(|text| !text.is_empty())
+                .collect()
+        };
+        let first = |selector: &Option<String>| texts(selector).into_iter().next();
+        TitleMeta {
+            authors: texts(&self.author_selector),
+            artists: texts(&self.artist_selector),
+            genres: texts(&self.genre_selector),
+            status: first(&self.status_selector).and_then(|status| TitleStatus::parse(&status)),
+            synopsis: first(&self.synopsis_selector),
+            alt_titles: texts(&self.alt_titles_selector),
+            rating: first(&self.rating_selector)
+                .and_then(|rating| rating.split_whitespace().find_map(|word| word.parse::<f64>().ok()))
+                .map(|rating| rating / self.rating_scale * 5.0),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for SelectorSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn handles(&self, host: &str) -> bool {
+        source::host_matches(host, &self.hosts)
+    }
+
+    fn serves_images(&self, host: &str) -> bool {
+        self.handles(host) || source::host_matches(host, &self.image_hosts)
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(referer) = &self.referer {
+            headers.insert(REFERER, HeaderValue::from_str(referer).unwrap());
+        }
+        headers
+    }
+
+    async fn fetch_title(&self, client: &http::Client, url: &str) -> Res<TitleInfo> {
+        let page_url = Url::parse(url).map_err(|e| AppError::BadRequest(e.to_string()))?;
+        let body = http::get_text(client, url).await?;
+        let document = Html::parse_document(&body);
+
+        let name = Self::select(&document, &self.title_selector).next()
+            .ok_or_else(|| AppError::parse("missing title name"))?
+            .text().collect::<String>().trim().to_string();
+
+        let cover_url = Self::select(&document, &self.cover_selector).next()
+            .and_then(|cover| cover.value().attr(&self.cover_attr))
+            .and_then(|src| page_url.join(src).ok())
+            .ok_or_else(|| AppError::parse("missing cover image"))?
+            .to_string();
+
+        let (chapters, last_updated) = self.parse_chapters(&document, &page_url);
+        let meta = self.parse_meta(&document);
+
+        Ok(TitleInfo { name, cover_url, last_updated, meta, chapters })
+    }
+
+    async fn fetch_images(&self, client: &http::Client, chapter_url: &str) -> Res<Vec<String>> {
+        let page_url = Url::parse(chapter_url).map_err(|e| AppError::BadRequest(e.to_string()))?;
+        let body = http::get_text(client, chapter_url).await?;
+        let document = Html::parse_document(&body);
+
+        Ok(Self::select(&document, &self.image_selector)
+            .filter_map(|element| element.value().attr(&self.image_attr))
+            .filter_map(|src| page_url.join(src.trim()).ok())
+            .map(|src| src.to_string())
+            .collect())
+    }
+}
@@ -0,0 +1,85 @@
+use std::{future::Future, sync::LazyLock, time::Duration};
+use tokio::{signal, task::JoinHandle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+// time unfinished downloads get to delete their partial files once the grace period is over
+const ROLLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// cancelled on Ctrl-C/SIGTERM: no new connections, background loops stop
+static STOPPING: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+// cancelled once the grace period is over: downloads still running roll back
+static ABORTED: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+// work the process waits for before exiting
+static TASKS: LazyLock<TaskTracker> = LazyLock::new(TaskTracker::new);
+
+/// Resolves on Ctrl-C or SIGTERM (or if something else already asked to stop)
+pub async fn signal() {
+    let ctrl_c = async { signal::ctrl_c().await.ok() };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => { sigterm.recv().await; }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => println!("Received Ctrl-C, shutting down..."),
+        _ = terminate => println!("Received SIGTERM, shutting down..."),
+        _ = STOPPING.cancelled() => {}
+    }
+    STOPPING.cancel();
+}
+
+pub fn is_stopping() -> bool {
+    STOPPING.is_cancelled()
+}
+
+/// Resolves once shutdown started, for the server's graceful shutdown
+pub async fn stopping() {
+    STOPPING.cancelled().await
+}
+
+/// Cancelled once the grace period is over and unfinished work should give up.
+/// Cancelling the returned token only stops the work it was handed to.
+pub fn abort_token() -> CancellationToken {
+    ABORTED.child_token()
+}
+
+/// Spawns work that shutdown waits for (up to the grace period)
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    TASKS.spawn(future)
+}
+
+/// Runs `future` unless shutdown starts first, for work that is safe to drop halfway (network scans)
+pub async fn unless_stopping<F: Future>(future: F) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = STOPPING.cancelled() => None,
+    }
+}
+
+/// Waits for the server's open requests and every spawned task.
+/// After `grace`, whatever is left is told to roll back and gets a few more seconds.
+pub async fn drain(server: impl Future<Output = ()>, grace: Duration) {
+    TASKS.close();
+    let finished = async {
+        server.await;
+        TASKS.wait().await;
+    };
+    if tokio::time::timeout(grace, finished).await.is_ok() {
+        println!("Shut down cleanly");
+        return;
+    }
+    println!("Still busy after {}s, rolling back {} unfinished tasks", grace.as_secs(), TASKS.len());
+    ABORTED.cancel();
+    if tokio::time::timeout(ROLLBACK_TIMEOUT, TASKS.wait()).await.is_err() {
+        println!("{} tasks did not finish rolling back", TASKS.len());
+    }
+}
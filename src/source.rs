@@ -0,0 +1,89 @@
+use std::sync::{Arc, RwLock};
+use axum::async_trait;
+use reqwest::{header::HeaderMap, Url};
+use crate::{http::Client, selector_source::SelectorSource, user::TitleMeta, error::Res};
+
+/// Everything a source knows about a title from its main page.
+/// Chapters are ordered oldest first.
+pub struct TitleInfo {
+    pub name: String,
+    pub cover_url: String,
+    pub last_updated: String, // "%Y-%m-%d"
+    pub meta: TitleMeta,
+    pub chapters: Vec<ChapterLink>,
+}
+
+/// A chapter as listed on the title page, metadata the site doesn't show stays None
+pub struct ChapterLink {
+    pub text: String,
+    pub url: String,
+    pub released: Option<String>, // "%Y-%m-%d"
+    pub group: Option<String>, // scanlation group or uploader
+    pub language: Option<String>,
+}
+
+/// A reader site we know how to scrape.
+/// `web.rs` owns the HTTP client and the storage side, a Source only knows
+/// where things live on its pages.
+#[async_trait]
+pub trait Source: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// True if URLs on this host (title pages and chapter pages) belong to this source
+    fn handles(&self, host: &str) -> bool;
+
+    /// True if the site serves page images or covers from this host (often a CDN).
+    /// Only these hosts can be fetched through /proxy.
+    fn serves_images(&self, host: &str) -> bool {
+        self.handles(host)
+    }
+
+    /// Extra headers the site requires (usually a Referer)
+    fn headers(&self) -> HeaderMap;
+
+    /// Also used by updates, which refresh the metadata along with the chapter list
+    async fn fetch_title(&self, client: &Client, url: &str) -> Res<TitleInfo>;
+
+    /// Image URLs of every page in a chapter, in reading order
+    async fn fetch_images(&self, client: &Client, chapter_url: &str) -> Res<Vec<String>>;
+}
+
+static SOURCES: RwLock<Vec<Arc<dyn Source>>> = RwLock::new(Vec::new());
+
+pub fn register(source: Arc<dyn Source>) {
+    println!("Registered source: {}", source.name());
+    SOURCES.write().unwrap().push(source);
+}
+
+/// Registers every *.toml / *.json site definition in `dir`.
+/// A broken definition is reported and skipped so one typo doesn't take the server down.
+pub async fn load_definitions(dir: &str) {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        println!("No source definitions at {dir}");
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        match SelectorSource::from_file(&entry.path()).await {
+            Ok(source) => register(Arc::new(source)),
+            Err(e) => println!("Skipping source definition {}: {e}", entry.path().display()),
+        }
+    }
+}
+
+/// Picks the source responsible for a title or chapter URL
+pub fn for_url(url: &str) -> Option<Arc<dyn Source>> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    SOURCES.read().unwrap().iter().find(|source| source.handles(host)).cloned()
+}
+
+/// Picks the source an image URL belongs to, None if no source serves images from its host
+pub fn for_image_url(url: &Url) -> Option<Arc<dyn Source>> {
+    let host = url.host_str()?;
+    SOURCES.read().unwrap().iter().find(|source| source.serves_images(host)).cloned()
+}
+
+/// `host` is one of `known` or a subdomain of one
+pub fn host_matches<S: AsRef<str>>(host: &str, known: &[S]) -> bool {
+    known.iter().any(|known| host == known.as_ref() || host.ends_with(&format!(".{}", known.as_ref())))
+}
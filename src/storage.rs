@@ -1,142 +1,255 @@
+use std::{path::PathBuf, sync::{atomic::{AtomicU64, Ordering}, Arc}};
 use axum::body::Bytes;
 use tokio::{
     fs::{create_dir, remove_dir_all, File},
     io::{AsyncWriteExt, AsyncReadExt, ErrorKind},
 };
-type Res<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+use crate::{chapter_diff::ChapterDiff, config::Paths, error::{AppError, Res}};
 
-pub const TITLE_PATH: &str = "./public/titles";
-// pub const USER_PATH: &str = "./public/users";
-pub const COVER_PATH: &str = "./public/covers";
+/// The titles and covers folders from the config, held in the app state
+#[derive(Clone, Debug)]
+pub struct Storage {
+    titles: Arc<str>,
+    covers: Arc<str>,
+}
 
-// ALL FILE/DIR MUST BE INTEGERS
-pub async fn read_directory_names(path: &str) -> Vec<u32> {
-    let mut contents = Vec::new();
-    let mut directory = tokio::fs::read_dir(path).await.unwrap();
-    
-    while let Some(entry) = directory.next_entry().await.unwrap() {
-        contents.push(entry.file_name().into_string().unwrap().parse::<u32>().unwrap());
-    }
+// numbers temporary files, two writers of the same path don't share one
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
 
-    contents
+/// Writes `{path}.{n}.tmp` then renames it over `path`, readers never see a half-written file
+pub async fn write_atomic(path: &str, bytes: &[u8]) -> Res<()> {
+    let temp = format!("{path}.{}.tmp", NEXT_TEMP.fetch_add(1, Ordering::Relaxed));
+    let result = async {
+        let mut file = File::create(&temp).await?;
+        file.write_all(bytes).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp, path).await
+    }.await;
+    if let Err(e) = result {
+        tokio::fs::remove_file(&temp).await.ok();
+        return Err(AppError::Storage(format!("could not write {path}: {e}")));
+    }
+    Ok(())
 }
 
 pub async fn open_json(path: &str) -> Res<String> {
     let mut file = File::open(path).await?;
     let mut content = String::new();
-    file.read_to_string(&mut content).await.unwrap();
+    file.read_to_string(&mut content).await?;
     Ok(content)
 }
 
-pub async fn save_json(path: &str, content: &str) {
-    let mut file = File::create(path).await.unwrap();
-    file.write_all(content.as_bytes()).await.unwrap();
+// Creating a folder that already exists is fine
+pub async fn create_dir_if_missing(path: String) -> Res<()> {
+    match create_dir(&path).await {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(AppError::Storage(format!("could not create {path}: {e}"))),
+        _ => Ok(()),
+    }
 }
 
-pub async fn setup_title(id: &u32) {
-    // Create Folder
-    if let Err(e) = create_dir(format!("{}/{}", TITLE_PATH, id)).await {
-        if e.kind() != ErrorKind::AlreadyExists {
-            panic!("storage::setup_title failed. Error: {}", e);
+// Removing a folder that doesn't exist is fine
+async fn remove_dir_if_present(path: String) -> Res<()> {
+    match remove_dir_all(&path).await {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            println!("Folder {path} not found.");
+            Ok(())
         }
+        Err(e) => Err(AppError::Storage(format!("could not remove {path}: {e}"))),
+        Ok(()) => Ok(()),
     }
 }
 
-pub async fn remove_title(id: &u32) {
-    // Remove Folder
-    if let Err(e) = remove_dir_all(format!("{}/{}", TITLE_PATH, id)).await {
-        if e.kind() == ErrorKind::NotFound {
-            println!("Folder id = {id} not found.")
-        } else {
-            panic!("storage::remove_title failed. Error: {}", e);
-        }
+// like remove_dir_if_present, for folders that usually don't exist
+async fn clear_dir(path: &str) -> Res<()> {
+    match remove_dir_all(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(AppError::Storage(format!("could not remove {path}: {e}"))),
+        _ => Ok(()),
     }
 }
 
-pub async fn save_cover(id: u32, cover: Bytes) {
-    // Save Cover
-    let mut cover_file = File::create(format!("{COVER_PATH}/{id}.jpeg")).await.unwrap();
-    cover_file.write_all(&cover).await.unwrap();
-}
+impl Storage {
+    pub fn new(paths: &Paths) -> Storage {
+        Storage { titles: paths.titles.as_str().into(), covers: paths.covers.as_str().into() }
+    }
 
-pub async fn clear_title(id: &u32) {
-    // Delete all chapters
-    match tokio::fs::read_dir(format!("{}/{}", TITLE_PATH, id)).await {
-        Ok(mut directory) => {
-            while let Some(entry) = directory.next_entry().await.unwrap() {
-                if entry.file_type().await.unwrap().is_dir() {
-                    remove_dir_all(entry.path()).await.unwrap();
-                }
-            }
-        },
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                println!("Folder id = {id} not found.")
-            } else {
-                panic!("storage::clear_title failed. Error: {}", e);
-            }
-        }
+    /// "./public/titles" unless configured otherwise
+    pub fn title_path(&self) -> &str {
+        &self.titles
+    }
+
+    pub fn cover_path(&self) -> &str {
+        &self.covers
+    }
+
+    pub async fn setup_title(&self, id: &u32) -> Res<()> {
+        create_dir_if_missing(format!("{}/{}", self.titles, id)).await
+    }
+
+    pub async fn remove_title(&self, id: &u32) -> Res<()> {
+        remove_dir_if_present(format!("{}/{}", self.titles, id)).await
+    }
+
+    pub async fn save_cover(&self, id: u32, cover: Bytes) -> Res<()> {
+        write_atomic(&format!("{}/{id}.jpeg", self.covers), &cover).await
     }
-}
 
-pub async fn setup_chapter(title_id: &u32, chapter_id: &u32) {
-    // Create Folder
-    if let Err(e) = create_dir(format!("{}/{}/{}", TITLE_PATH, title_id, chapter_id)).await {
-        if e.kind() != ErrorKind::AlreadyExists {
-            panic!("storage::setup_chapter failed. Error: {}", e);
+    pub async fn has_cover(&self, id: u32) -> bool {
+        tokio::fs::try_exists(format!("{}/{id}.jpeg", self.covers)).await.unwrap_or(false)
+    }
+
+    pub async fn remove_cover(&self, id: u32) -> Res<()> {
+        match tokio::fs::remove_file(format!("{}/{id}.jpeg", self.covers)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
         }
     }
-}
 
-pub async fn delete_chapter(title_id: &u32, chapter_id: &u32) {
-    // Remove Folder
-    if let Err(e) = remove_dir_all(format!("{}/{}/{}", TITLE_PATH, title_id, chapter_id)).await {
-        if e.kind() == ErrorKind::NotFound {
-            println!("Folder id = {title_id} not found.")
-        } else {
-            panic!("storage::delete_chapter failed. Error: {}", e);
+    // Covers saved before the shared catalog were keyed by per-user title ids
+    pub async fn retire_covers(&self) -> Res<()> {
+        let mut covers = match tokio::fs::read_dir(&*self.covers).await {
+            Ok(covers) => covers,
+            Err(e) if e.kind() == ErrorKind::NotFound => return create_dir_if_missing(self.covers.to_string()).await,
+            Err(e) => return Err(e.into()),
+        };
+        if covers.next_entry().await?.is_none() {
+            return Ok(());
         }
+        tokio::fs::rename(&*self.covers, format!("{}.legacy", self.covers)).await?;
+        create_dir_if_missing(self.covers.to_string()).await
     }
-}
 
-pub async fn get_num_images(title_id: u32, chapter_id: u32) -> u32 {
-    let mut num_images = 0;
-    match tokio::fs::read_dir(format!("{}/{}/{}", TITLE_PATH, title_id, chapter_id)).await {
-        Ok(mut directory) => {
-            while let Some(entry) = directory.next_entry().await.unwrap() {
-                if entry.file_type().await.unwrap().is_file() {
-                    num_images += 1;
-                }
+    // pages are downloaded next to the chapter and only renamed into place once all of them arrived
+    fn partial_chapter_path(&self, title_id: u32, chapter_id: u32) -> String {
+        format!("{}/{title_id}/{chapter_id}.part", self.titles)
+    }
+
+    /// Folder for a download, returns its path. Pages an earlier attempt saved are kept.
+    pub async fn begin_chapter(&self, title_id: u32, chapter_id: u32) -> Res<String> {
+        let path = self.partial_chapter_path(title_id, chapter_id);
+        create_dir_if_missing(path.clone()).await?;
+        Ok(path)
+    }
+
+    /// Moves a finished download into place, replacing an older copy
+    pub async fn commit_chapter(&self, title_id: u32, chapter_id: u32) -> Res<()> {
+        let path = format!("{}/{title_id}/{chapter_id}", self.titles);
+        clear_dir(&path).await?;
+        tokio::fs::rename(self.partial_chapter_path(title_id, chapter_id), &path).await
+            .map_err(|e| AppError::Storage(format!("could not move {path} into place: {e}")))
+    }
+
+    /// Drops an unfinished download
+    pub async fn discard_chapter(&self, title_id: u32, chapter_id: u32) -> Res<()> {
+        clear_dir(&self.partial_chapter_path(title_id, chapter_id)).await
+    }
+
+    /// Moves downloaded chapters to their new index after an update.
+    /// Removed and re-uploaded chapters are deleted, their pages are gone or changed upstream.
+    pub async fn remap_chapters(&self, title_id: u32, diff: &ChapterDiff) -> Res<()> {
+        let title = format!("{}/{title_id}", self.titles);
+        let stale = diff.removed.iter().map(|removed| removed.index).chain(diff.reuploaded.iter().map(|reuploaded| reuploaded.old_index));
+        for old in stale {
+            clear_dir(&format!("{title}/{old}")).await?;
+        }
+        // pages kept from failed attempts belong to whatever chapter had the index back then
+        for (old, new) in (0u32..).zip(&diff.remap) {
+            if *new != Some(old) || diff.reuploaded.iter().any(|reuploaded| reuploaded.old_index == old) {
+                clear_dir(&self.partial_chapter_path(title_id, old)).await?;
+            }
+        }
+
+        // through "{new}.moving" so a chapter never lands on one that hasn't moved yet
+        let mut moved = Vec::new();
+        for (old, new) in (0u32..).zip(&diff.remap) {
+            let Some(new) = *new else { continue; };
+            if old == new || diff.reuploaded.iter().any(|reuploaded| reuploaded.old_index == old) {
+                continue;
             }
-        },
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                println!("Folder id = {title_id} not found.")
-            } else {
-                panic!("storage::get_num_images failed. Error: {}", e);
+            match tokio::fs::rename(format!("{title}/{old}"), format!("{title}/{new}.moving")).await {
+                Ok(()) => moved.push(new),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
             }
         }
+        for new in moved {
+            clear_dir(&format!("{title}/{new}")).await?;
+            tokio::fs::rename(format!("{title}/{new}.moving"), format!("{title}/{new}")).await?;
+        }
+        Ok(())
     }
-    num_images
-}
 
-pub async fn get_chapters(title_id: u32) -> Vec<u32> {
-    let mut chapters = Vec::new();
-    match tokio::fs::read_dir(format!("{}/{}", TITLE_PATH, title_id)).await {
-        Ok(mut directory) => {
-            while let Some(entry) = directory.next_entry().await.unwrap() {
-                if entry.file_type().await.unwrap().is_dir() {
-                    chapters.push(entry.file_name().into_string().unwrap().parse::<u32>().unwrap());
+    /// Leftovers from a crash or a killed process: `*.part` and `*.moving` chapters, `*.tmp` files.
+    /// `*.part` chapters `resumed(title_id, chapter_id)` keeps are left for the download to finish.
+    pub async fn remove_partial_files(&self, resumed: impl Fn(u32, u32) -> bool) -> Res<()> {
+        let mut removed = 0;
+        let mut folders = vec![(self.titles.to_string(), None), (self.covers.to_string(), None)];
+        let mut titles = match tokio::fs::read_dir(&*self.titles).await {
+            Ok(titles) => titles,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(title) = titles.next_entry().await? {
+            if title.file_type().await?.is_dir() {
+                let title_id = title.file_name().to_str().and_then(|name| name.parse::<u32>().ok());
+                folders.push((title.path().to_string_lossy().to_string(), title_id));
+            }
+        }
+        for (folder, title_id) in folders {
+            let Ok(mut entries) = tokio::fs::read_dir(&folder).await else { continue; };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if let Some(chapter) = name.strip_suffix(".part") {
+                    let chapter_id = chapter.parse::<u32>().ok();
+                    if title_id.zip(chapter_id).is_some_and(|(title_id, chapter_id)| resumed(title_id, chapter_id)) {
+                        continue;
+                    }
+                    remove_dir_all(entry.path()).await?;
+                } else if name.ends_with(".moving") {
+                    remove_dir_all(entry.path()).await?;
+                } else if name.ends_with(".tmp") {
+                    tokio::fs::remove_file(entry.path()).await?;
+                } else {
+                    continue;
                 }
+                removed += 1;
             }
-        },
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                println!("Folder id = {title_id} not found.")
-            } else {
-                panic!("storage::get_chapters failed. Error: {}", e);
+        }
+        if removed > 0 {
+            println!("Removed {removed} unfinished downloads");
+        }
+        Ok(())
+    }
+
+    pub async fn delete_chapter(&self, title_id: &u32, chapter_id: &u32) -> Res<()> {
+        remove_dir_if_present(format!("{}/{}/{}", self.titles, title_id, chapter_id)).await
+    }
+
+    pub async fn get_num_images(&self, title_id: u32, chapter_id: u32) -> Res<u32> {
+        let mut num_images = 0;
+        let mut directory = tokio::fs::read_dir(format!("{}/{}/{}", self.titles, title_id, chapter_id)).await?;
+        while let Some(entry) = directory.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                num_images += 1;
             }
         }
+        Ok(num_images)
+    }
+
+    /// True once a chapter has at least one downloaded page
+    pub async fn has_chapter(&self, title_id: u32, chapter_id: u32) -> bool {
+        matches!(self.get_num_images(title_id, chapter_id).await, Ok(n) if n > 0)
     }
-    chapters
-}
\ No newline at end of file
+
+    /// Downloaded pages of a chapter in reading order ("0.jpeg", "1.jpeg", ... "10.jpeg")
+    pub fn chapter_pages(&self, title_id: u32, chapter_id: u32) -> Res<Vec<PathBuf>> {
+        let mut pages: Vec<(u32, PathBuf)> = std::fs::read_dir(format!("{}/{title_id}/{chapter_id}", self.titles))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let page = entry.path().file_stem()?.to_str()?.parse::<u32>().ok()?;
+                Some((page, entry.path()))
+            })
+            .collect();
+        pages.sort_by_key(|(page, _)| *page);
+        Ok(pages.into_iter().map(|(_, path)| path).collect())
+    }
+}
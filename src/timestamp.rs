@@ -5,13 +5,12 @@ pub fn get_time() -> String {
     now.format("%Y-%m-%d").to_string()
 }
 
-pub fn get_nelo_time(date: &str) -> String {
-    chrono::NaiveDate::parse_from_str(date, "%b %d,%y").unwrap().format("%Y-%m-%d").to_string()
+pub fn get_nelo_time(date: &str) -> Option<String> {
+    parse_date(date, "%b %d,%y")
+}
+
+// Same as get_nelo_time for sites with their own date format
+pub fn parse_date(date: &str, format: &str) -> Option<String> {
+    chrono::NaiveDate::parse_from_str(date.trim(), format).ok().map(|date| date.format("%Y-%m-%d").to_string())
 }
 
-pub fn get_duration(start: String, end: String) -> i32 {
-    let start = chrono::NaiveDate::parse_from_str(&start, "%Y-%m-%d").unwrap();
-    let end = chrono::NaiveDate::parse_from_str(&end, "%Y-%m-%d").unwrap();
-    let duration = end - start;
-    duration.num_days() as i32
-}
\ No newline at end of file
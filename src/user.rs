@@ -1,153 +1,316 @@
-use std::{error::Error, collections::{HashSet, HashMap}, hash::Hash};
+use std::{collections::{HashMap, HashSet}, ops::RangeInclusive};
 use serde::{Deserialize, Serialize};
-use tokio::fs;
-use crate::{storage, timestamp::get_time};
-
-const USERS_PATH: &str = "./public/users";
-type Res<T> = Result<T, Box<dyn Error>>;
+use crate::{auth, db, library::SystemTitle, timestamp::get_time, error::{AppError, Res}};
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct User {
     pub id: u32,
     pub username: String,
-    pub password: String, // doubles as error message
+    pub password: String, // argon2 hash
     pub titles: Vec<Title>,
     pub tags: HashMap<String, Vec<u32>> // tag_name -> [title ids]
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The credentials of a user without their library, what every authenticated request loads.
+/// Progress, follows and tags are written through it; handlers that list titles load the `User`.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub id: u32,
+    pub username: String,
+    pub password: String, // argon2 hash
+    pub token_generation: u32, // bumped to revoke every token issued before
+}
+
+/// A catalog title as seen by one user: shared metadata plus their own progress and tags
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Title {
     pub id: u32,
     pub name: String,
     pub url: String,
     pub chap_prefix: String, // "...com/"
-    pub last_chap: u32,
+    pub last_chap: u32, // reading position: the chapter last opened, not necessarily read
+    #[serde(default)]
+    pub last_page: u32, // page within last_chap
+    #[serde(default)]
+    pub read: Vec<u32>, // indices of chapters marked read, the only source of read state
     pub last_updated: String, // Actual Release Date
     pub last_read: String, // User Read Date
     pub last_scanned: String, // When Axum scanned
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub meta: TitleMeta,
     pub chapters: Vec<Chapter>
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// What the source says about a title besides its chapters, empty where it doesn't say
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TitleMeta {
+    pub authors: Vec<String>,
+    pub artists: Vec<String>,
+    pub genres: Vec<String>,
+    pub status: Option<TitleStatus>,
+    pub synopsis: Option<String>,
+    pub alt_titles: Vec<String>,
+    pub rating: Option<f64>, // out of 5
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TitleStatus {
+    Ongoing,
+    Completed,
+    Hiatus,
+    Cancelled,
+}
+
+/// Files written before number..language existed still load, the fields default to None
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Chapter {
     pub t: String, // text description
     pub s: String, // suffix "chapter-1"
-    pub i: u32, // number of images
+    pub i: u32, // number of images, 0 until counted (see page_counts.rs)
+    #[serde(default)]
+    pub counted: bool, // `i` is known, some chapters really have no pages
+    #[serde(default)]
+    pub number: Option<f64>, // 10.5 for "Chapter 10.5"
+    #[serde(default)]
+    pub volume: Option<String>, // "3" for "Vol.3 Chapter 10"
+    #[serde(default)]
+    pub released: Option<String>, // "%Y-%m-%d"
+    #[serde(default)]
+    pub group: Option<String>, // scanlation group or uploader
+    #[serde(default)]
+    pub language: Option<String>, // "en"
+}
+
+/// A user's position in one title, returned by the progress endpoints
+#[derive(Serialize, Debug)]
+pub struct Progress {
+    pub title_id: u32,
+    pub last_chap: u32,
+    pub last_page: u32,
+    pub last_read: String,
+    pub read: Vec<u32>,
+}
+
+/// One unread chapter in a user's updates inbox
+#[derive(Serialize, Debug)]
+pub struct FeedEntry {
+    pub title_id: u32,
+    pub title: String,
+    pub chapter_id: u32, // index into Title.chapters
+    pub text: String,
+    pub url: String,
+    pub released: String, // "%Y-%m-%d"
+}
+
+/// Query of GET /library, a title has to match every filter given (case-insensitive)
+#[derive(Deserialize, Debug, Default)]
+pub struct LibraryFilter {
+    pub q: Option<String>, // part of the name or an alternative title
+    pub author: Option<String>, // part of an author or artist name
+    pub genre: Option<String>, // "action,drama": has all of them
+    pub status: Option<TitleStatus>,
+    pub tag: Option<String>,
+    pub min_rating: Option<f64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct DB {
-    users: HashMap<String, u32>,
-    titles: HashMap<String, u32>,
+#[derive(Serialize, Debug)]
+pub struct Feed {
+    pub unread: HashMap<u32, u32>, // title_id -> unread chapters
+    pub chapters: Vec<FeedEntry>, // newest first
 }
 
-impl DB {
-    async fn new() -> DB {
-        let json_str = storage::open_json("./public/db.json").await.unwrap();
-        let db = serde_json::from_str::<DB>(&json_str).unwrap();
-        db
+impl TitleStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TitleStatus::Ongoing => "ongoing",
+            TitleStatus::Completed => "completed",
+            TitleStatus::Hiatus => "hiatus",
+            TitleStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Reads a site's wording: "Ongoing", "Completed", "Finished", "On Hiatus", "Discontinued"...
+    pub fn parse(status: &str) -> Option<TitleStatus> {
+        let status = status.to_lowercase();
+        [
+            (TitleStatus::Ongoing, &["ongoing", "publishing", "releasing"][..]),
+            (TitleStatus::Completed, &["complete", "finished", "ended"]),
+            (TitleStatus::Hiatus, &["hiatus"]),
+            (TitleStatus::Cancelled, &["cancel", "discontinued", "dropped"]),
+        ]
+        .into_iter()
+        .find(|(_, words)| words.iter().any(|word| status.contains(word)))
+        .map(|(known, _)| known)
     }
-    async fn save(&self) {
-        let json_str = serde_json::to_string(self).unwrap();
-        storage::save_json("./public/db.json", &json_str).await;
+}
+
+impl LibraryFilter {
+    pub fn matches(&self, title: &Title) -> bool {
+        let contains = |text: &str, part: &str| text.to_lowercase().contains(&part.to_lowercase());
+        let meta = &title.meta;
+        self.q.as_ref().is_none_or(|q| contains(&title.name, q) || meta.alt_titles.iter().any(|alt| contains(alt, q)))
+            && self.author.as_ref().is_none_or(|author| meta.authors.iter().chain(&meta.artists).any(|name| contains(name, author)))
+            && self.genre.as_ref().is_none_or(|genres| genres.split(',').map(str::trim).filter(|genre| !genre.is_empty())
+                .all(|genre| meta.genres.iter().any(|known| known.eq_ignore_ascii_case(genre))))
+            && self.status.is_none_or(|status| meta.status == Some(status))
+            && self.tag.as_ref().is_none_or(|tag| title.tags.contains(tag))
+            && self.min_rating.is_none_or(|min| meta.rating.is_some_and(|rating| rating >= min))
     }
 }
 
+impl Title {
+    // chapters not marked read, wherever the reading position is
+    pub fn unread_chapters(&self) -> impl Iterator<Item = (u32, &Chapter)> {
+        let read: HashSet<u32> = self.read.iter().copied().collect();
+        (0u32..).zip(&self.chapters).filter(move |(index, _)| !read.contains(index))
+    }
+}
+
+impl Chapter {
+    /// Reads (number, volume) from labels like "Vol.3 Chapter 10.5: Title", "Ch.12" or "Episode 5".
+    /// Without a chapter keyword the first number counts, unless it was the volume.
+    pub fn parse_label(t: &str) -> (Option<f64>, Option<String>) {
+        let label = t.to_ascii_lowercase();
+        let volume = number_after(&label, &["volume", "vol"]);
+        let number = number_after(&label, &["chapter", "chap", "ch", "episode", "ep"])
+            .or_else(|| volume.is_none().then(|| first_number(&label)).flatten());
+        (number.and_then(|number| number.parse().ok()), volume.map(str::to_string))
+    }
+}
+
+// the number following one of `keywords` at the start of a word: "vol.3", "chapter 10.5", "ch#4"
+fn number_after<'a>(label: &'a str, keywords: &[&str]) -> Option<&'a str> {
+    keywords.iter().find_map(|keyword| label.match_indices(keyword).find_map(|(at, _)| {
+        let starts_word = !label[..at].ends_with(|c: char| c.is_ascii_alphanumeric());
+        let rest = label[at + keyword.len()..].trim_start_matches([' ', '.', ':', '#', '-', '_']);
+        starts_word.then(|| leading_number(rest)).flatten()
+    }))
+}
+
+fn first_number(label: &str) -> Option<&str> {
+    leading_number(&label[label.find(|c: char| c.is_ascii_digit())?..])
+}
+
+// "10.5: Title" -> "10.5", None unless `text` starts with a digit
+fn leading_number(text: &str) -> Option<&str> {
+    let end = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let number = text[..end].trim_end_matches('.');
+    (number.starts_with(|c: char| c.is_ascii_digit()) && number.matches('.').count() <= 1).then_some(number)
+}
+
 impl User {
-    pub fn empty_with_message(message: String) -> User {
-        User {
-            id: 0,
-            username: String::new(),
-            password: message,
-            titles: Vec::new(),
-            tags: HashMap::new(),
-        }
+    /// Unread chapters across every followed title, newest release first
+    pub fn feed(&self, limit: Option<usize>) -> Feed {
+        let unread = self.titles.iter()
+            .map(|title| (title.id, title.unread_chapters().count() as u32))
+            .collect();
+
+        let mut chapters: Vec<FeedEntry> = self.titles.iter()
+            .flat_map(|title| title.unread_chapters().map(move |(chapter_id, chapter)| FeedEntry {
+                title_id: title.id,
+                title: title.name.clone(),
+                chapter_id,
+                text: chapter.t.clone(),
+                url: format!("{}{}", title.chap_prefix, chapter.s),
+                released: chapter.released.clone().unwrap_or_else(|| title.last_updated.clone()),
+            }))
+            .collect();
+        chapters.sort_by(|a, b| b.released.cmp(&a.released).then(b.chapter_id.cmp(&a.chapter_id)));
+        chapters.truncate(limit.unwrap_or(usize::MAX));
+
+        Feed { unread, chapters }
     }
 
-    // register new user instance
-    pub async fn new(username: String, password: String) -> Option<User> {
-        // check if username already exists
-        let mut db = DB::new().await;
-        if db.users.contains_key(&username) {
-            println!("Username already exists: {}", username);
-            return None;
-        }
+    /// Followed titles matching `filter`, in library order
+    pub fn library(&self, filter: &LibraryFilter) -> Vec<Title> {
+        self.titles.iter().filter(|title| filter.matches(title)).cloned().collect()
+    }
 
-        // find suitable ID
-        let user_ids = storage::read_directory_names(USERS_PATH).await;
-        let set: HashSet<u32> = user_ids.into_iter().collect();
-        let id = (0..).find(|i| !set.contains(i)).unwrap();
+    // register new user instance
+    pub fn new(username: String, password: &str) -> Res<User> {
+        db::store().create_user(&username, &auth::hash_password(password))
+    }
 
-        // add to db
-        db.users.insert(username.clone(), id);
-        db.save().await;
+    pub fn from_id(id: u32) -> Res<User> {
+        db::store().load_user(id)?.ok_or_else(|| AppError::not_found("User Does Not Exist"))
+    }
 
-        Some(User {
-            id,
-            username,
-            password,
-            titles: Vec::new(),
-            tags: HashMap::new(),
-        })
+    // copy safe to send to the client
+    pub fn without_password(mut self) -> User {
+        self.password.clear();
+        self
     }
 
-    // load existing user from disk
-    pub async fn from(name: &str) -> Option<User> {
-        // check db
-        let db = DB::new().await;
-        let Some(id) = db.users.get(name) else { return None; };
+    pub fn has_title_url(&self, url: &str) -> bool {
+        self.titles.iter().any(|title| url == title.url)
+    }
+}
 
-        let content = storage::open_json(&format!("{}/{}.json", USERS_PATH, id)).await.unwrap();
-        let user: User = serde_json::from_str(&content).unwrap();
-        Some(user)
+impl Account {
+    pub fn from(name: &str) -> Res<Account> {
+        let id = db::store().find_user_id(name)?.ok_or_else(|| AppError::not_found("User Does Not Exist"))?;
+        Account::from_id(id)
     }
 
-    pub async fn save_to_disk(&self) -> Res<()> {
-        let string = serde_json::to_string(self)?;
-        storage::save_json(&format!("{USERS_PATH}/{}.json", self.id), &string).await;
-        Ok(())
+    pub fn from_id(id: u32) -> Res<Account> {
+        db::store().load_account(id)?.ok_or_else(|| AppError::not_found("User Does Not Exist"))
     }
 
-    pub async fn delete_from_disk(&self) -> Res<()> {
-        // Check if user exists in DB and remove
-        let mut db = DB::new().await;
-        let Some(user_id) = db.users.remove(&self.username) else {return Ok(());};
-        db.save().await;
+    // with every followed title
+    pub fn user(&self) -> Res<User> {
+        User::from_id(self.id)
+    }
 
-        // Delete user.json if possible
-        fs::remove_file(&format!("{USERS_PATH}/{user_id}.json")).await?;
+    // accounts created before hashing still hold the plaintext password
+    pub fn check_password(&self, password: &str) -> bool {
+        if auth::is_legacy_password(&self.password) {
+            self.password == password
+        } else {
+            auth::verify_password(password, &self.password)
+        }
+    }
 
+    // signs out every session, issue a new token afterwards
+    pub fn set_password(&mut self, password: &str) -> Res<()> {
+        self.password = auth::hash_password(password);
+        self.token_generation = db::store().set_password(self.id, &self.password)?;
         Ok(())
     }
 
-    pub fn add_title(&mut self, name: String, url: String, chap_prefix: String, last_updated: String, chapters: Vec<Chapter>) -> Res<u32> {
+    pub fn delete(&self) -> Res<()> {
+        db::store().delete_user(self.id)
+    }
+
+    // progress updates go straight to the store, no full user rewrite
+    pub fn mark_read(&self, title_id: u32, chapters: RangeInclusive<u32>, read: bool) -> Res<Progress> {
+        if chapters.is_empty() {
+            return Err(AppError::BadRequest("Empty chapter range".to_string()));
+        }
+        db::store().set_chapters_read(self.id, title_id, chapters, read, &get_time())
+    }
 
-        // generate new ID
-        let title_ids = self.titles.iter().map(|title| title.id).collect::<HashSet<u32>>();
-        let new_title_id = (0..).find(|i| !title_ids.contains(i)).unwrap();
+    pub fn set_page(&self, title_id: u32, chapter_id: u32, page: u32) -> Res<Progress> {
+        db::store().set_page(self.id, title_id, chapter_id, page, &get_time())
+    }
 
-        self.titles.push(Title {
-            id: new_title_id,
-            name,
-            url,
-            chap_prefix,
-            last_chap: 0,
-            last_updated,
-            last_read: get_time(),
-            last_scanned: get_time(),
-            tags: Vec::new(),
-            chapters,
-        });
+    pub fn follows(&self, title_id: u32) -> Res<bool> {
+        db::store().follows(self.id, title_id)
+    }
 
-        Ok(new_title_id)
+    // follow a catalog title, false if it already was
+    pub fn follow(&self, title: &SystemTitle) -> Res<bool> {
+        db::store().follow_title(self.id, title.id, &get_time())
     }
 
-    pub fn has_title_url(&mut self, url: &str) -> bool {
-        self.titles.iter().any(|title| url == title.url)
+    // false if the title wasn't followed
+    pub fn unfollow(&self, title_id: u32) -> Res<bool> {
+        db::store().unfollow_title(self.id, title_id)
     }
 
-    pub fn remove_title(&mut self, id: u32) {
-        self.titles.retain(|title| title.id != id);
+    pub fn set_tags(&self, tags: &HashMap<String, Vec<u32>>) -> Res<()> {
+        db::store().set_tags(self.id, tags)
     }
-}
\ No newline at end of file
+}
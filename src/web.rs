@@ -1,226 +1,198 @@
-use std::error::Error;
+use std::sync::Arc;
 use axum::body::Bytes;
-use reqwest::{
-    header::{HeaderMap, HeaderValue, REFERER, USER_AGENT},
-    Client,
-};
-use scraper::{Html, Selector};
-use futures::future::join_all;
-use crate::{latency::{Latency, self}, user::{Chapter, Title}, timestamp};
-
-type Res<T> = Result<T, Box<dyn Error + Send + Sync>>;
-
-
-pub async fn create_client() -> Client {
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        REFERER,
-        HeaderValue::from_static("https://manganato.com/"),
-    );
-    headers.insert(USER_AGENT, HeaderValue::from_static("Mozilla/5.0"));
-    Client::builder()
-        .default_headers(headers)
-        .build()
-        .unwrap()
+use reqwest::header::{HeaderValue, USER_AGENT};
+use crate::{cache, config, http::{self, Client}, storage, latency::Latency, user::{Chapter, TitleMeta}, library::SystemTitle, source::{self, ChapterLink, Source}, timestamp, error::{AppError, Res}};
+
+/// The User-Agent and timeouts every request to a source goes out with, held in the app state
+#[derive(Clone)]
+pub struct Fetcher {
+    user_agent: HeaderValue,
+    settings: http::Settings,
 }
 
-pub struct WebResult {
-    pub title: String,
-    pub chap_prefix: String,
-    pub last_updated: String,
-    pub chapters: Vec<Chapter>,
-    pub cover: Bytes,
-}
-/// Heavy and Expensive function. Scrapes:
-/// - Basic Details and URLs
-/// - Number of images per chapter
-/// - Cover Image Data
-pub async fn extract_title(url: &str) -> WebResult {
-    // Persistent Variables
-    let mut timer = Latency::new("extract_title");
-    let title: String;
-    let chap_prefix: String;
-    let last_updated: String;
-
-    let cover_url: String;
-    let client = create_client().await;
-    let mut links: Vec<(String, String)>;
-    let handles: Vec<tokio::task::JoinHandle<u32>>;
-    
-    // Contain !Send Types (Html, Selector)
-    {
-        let response = client.get(url).send().await.unwrap();
-        timer.tick("got page HTML");
-
-        let body = response.text().await.unwrap();
-        let document = Html::parse_document(&body);
-
-        let title_selector = Selector::parse(".story-info-right > h1").unwrap();
-        let cover_selector = Selector::parse(".info-image > .img-loading").unwrap();
-        let link_selector = Selector::parse(".row-content-chapter > li > a").unwrap();
-        let date_released_selector = Selector::parse(".row-content-chapter > li > span").unwrap();
-
-        title = document.select(&title_selector).next().unwrap()
-            .text().collect::<String>();
-
-        cover_url = document.select(&cover_selector).next().unwrap()
-            .value().attr("src").unwrap().to_string();
-
-        let most_recent_date = document.select(&date_released_selector).nth(1).unwrap()
-            .text().collect::<String>();
-        last_updated = timestamp::get_nelo_time(&most_recent_date);
-
-        // Get Chapter URLs and Description --- Extract Prefix/Suffix
-        // Ex. https://manganato.com/manga-ai118410/chapter-1 
-        // --> chap_prefix = "https://manganato.com/manga-ai118410/"
-        // --> s (or suffix) = "chapter-1"
-        links = document.select(&link_selector)
-            .map(|link| 
-                (link.text().collect::<String>(), link.value().attr("href").unwrap().to_string())
-            ).collect();
-        links.reverse(); // 3,2,1 -> 1,2,3
-
-        chap_prefix = links.get(0).unwrap().1.rsplit_once("/").unwrap().0.to_string() + "/";
-
-        // Get Num Images per Chapter
-        handles = links.iter().map(|tuple|
-            tokio::spawn(get_num_images(client.clone(), tuple.1.clone()))
-        ).collect();
-        timer.tick("done scraping HTML");
+impl Fetcher {
+    pub fn new(config: &config::Http) -> Fetcher {
+        Fetcher {
+            user_agent: HeaderValue::from_str(&config.user_agent).expect("validated with the config"),
+            settings: config.settings(),
+        }
     }
 
-    // Download Cover
-    let cover_bytes: Bytes = client.get(cover_url).send().await.unwrap()
-        .bytes().await.unwrap();
-    timer.tick("done downloading cover image");
-
-    // Multithread Scout Chapter Img Count
-    let results: Vec<Result<u32, JoinError>> = join_all(handles).await;
-    timer.tick("all threads finished scouting chapter image count");
-    let mut chapters: Vec<Chapter> = Vec::new();
-    for (i, (text, url)) in links.into_iter().enumerate() {
-        chapters.push(Chapter {
-            t: text,
-            s: url.rsplit_once("/").unwrap().1.to_string(),
-            i: results.get(i).unwrap().as_ref().unwrap().clone(),
-        });
+    pub fn user_agent(&self) -> HeaderValue {
+        self.user_agent.clone()
     }
 
-    WebResult {
-        title,
-        chap_prefix,
-        last_updated,
-        chapters,
-        cover: cover_bytes,
+    /// Adds the retry settings to a client built elsewhere, like the proxy's pinned one
+    pub fn wrap(&self, client: reqwest::Client) -> Client {
+        Client::new(client, self.settings.clone())
     }
-}
 
-// Updates title directly and returns None if no new chapters
-pub async fn update_title(title: &mut Title) -> Option<()> {
-    let mut latency = Latency::new("update_title");
-    let client = create_client().await;
-    let response = client.get(&title.url).send().await.unwrap();
-    latency.tick("got page HTML");
-
-    let mut links: Vec<(String, String)>;
-    let most_recent_date: String;
-    // get new data
-    {
-        let document = Html::parse_document(&response.text().await.unwrap());
-        let link_selector = Selector::parse(".row-content-chapter > li > a").unwrap();
-        let date_released_selector = Selector::parse(".row-content-chapter > li > span").unwrap();
-
-        links = document.select(&link_selector)
-            .map(|link| 
-                (link.text().collect::<String>(), link.value().attr("href").unwrap().to_string())
-            ).collect();
-        links.reverse();
-
-        most_recent_date = document.select(&date_released_selector).nth(1).unwrap()
-            .text().collect::<String>();
+    fn create_client(&self, source: &dyn Source) -> Client {
+        let mut headers = source.headers();
+        headers.insert(USER_AGENT, self.user_agent());
+        // read timeouts and retries are in http.rs
+        self.wrap(reqwest::Client::builder()
+            .default_headers(headers)
+            .connect_timeout(self.settings.connect_timeout)
+            .build()
+            .unwrap())
     }
 
-    // update title
-    title.last_scanned = timestamp::get_nelo_time(&most_recent_date);
+    /// Scrapes:
+    /// - Basic Details and URLs
+    /// - Cover Image Data
+    ///
+    /// Page counts are left at 0, see page_counts.rs
+    pub async fn extract_title(&self, url: &str) -> Res<WebResult> {
+        let mut timer = Latency::new("extract_title");
+        let source = find_source(url)?;
+        let client = self.create_client(source.as_ref());
+
+        let info = source.fetch_title(&client, url).await?;
+        timer.tick("done scraping HTML");
 
-    if links.len() == title.chapters.len() {
-        return None;
+        // Extract Prefix/Suffix
+        // Ex. https://manganato.com/manga-ai118410/chapter-1
+        // --> chap_prefix = "https://manganato.com/manga-ai118410/"
+        // --> s (or suffix) = "chapter-1"
+        let chap_prefix = info.chapters.first().ok_or_else(|| AppError::parse("title has no chapters"))?
+            .url.rsplit_once('/').ok_or_else(|| AppError::parse("malformed chapter url"))?.0.to_string() + "/";
+
+        // Download Cover
+        let cover_bytes: Bytes = http::get_bytes(&client, &info.cover_url).await?;
+        timer.tick("done downloading cover image");
+
+        let chapters: Vec<Chapter> = info.chapters.iter().map(|link| to_chapter(link, 0)).collect();
+
+        Ok(WebResult {
+            title: info.name,
+            chap_prefix,
+            last_updated: info.last_updated,
+            meta: info.meta,
+            chapters,
+            cover: cover_bytes,
+        })
     }
 
-    title.last_updated = title.last_scanned.clone();
-
-    for (i, (text, url)) in links.into_iter().enumerate() {
-        if i >= title.chapters.len() {
-            title.chapters.push(Chapter {
-                t: text,
-                s: url.rsplit_once("/").unwrap().1.to_string(),
-                i: get_num_images(client.clone(), url).await,
-            });
-        }
+    /// Rescrapes the chapter list and title metadata into `title`, chapters start with 0 pages like on add.
+    /// db::Store::save_catalog_title reconciles them with the stored list.
+    pub async fn update_title(&self, title: &mut SystemTitle) -> Res<()> {
+        let mut latency = Latency::new("update_title");
+        let source = find_source(&title.url)?;
+        let client = self.create_client(source.as_ref());
+        let info = source.fetch_title(&client, &title.url).await?;
+        latency.tick("got chapter list");
+
+        // page counts and the like are carried over when saving, against the stored list
+        title.last_scanned = timestamp::get_time();
+        title.last_updated = info.last_updated;
+        title.meta = info.meta;
+        title.chapters = info.chapters.iter().map(|link| to_chapter(link, 0)).collect();
+        Ok(())
     }
 
-    Some(())
-}
-
-async fn get_num_images(client: Client, url: String) -> u32 {
-    let response = client.get(url).send().await.unwrap();
-    let body = response.text().await.unwrap();
-    let document = Html::parse_document(&body);
+    // Only the cover, for titles we already know
+    pub async fn fetch_cover(&self, url: &str) -> Res<Bytes> {
+        let source = find_source(url)?;
+        let client = self.create_client(source.as_ref());
+        let info = source.fetch_title(&client, url).await?;
+        http::get_bytes(&client, &info.cover_url).await
+    }
 
-    let selector = Selector::parse(".container-chapter-reader > img").unwrap();
-    document.select(&selector).count() as u32
-}
+    pub async fn count_pages(&self, chapter_url: &str) -> Res<u32> {
+        Ok(self.get_images_src(chapter_url).await?.len() as u32)
+    }
 
-pub async fn get_images_src(chapter_url: &str) -> Res<Vec<String>> {
-    let client = create_client().await;
-    let response = client.get(chapter_url).send().await?;
-    let document = Html::parse_document(&response.text().await?);
-    let css_selector = Selector::parse(".container-chapter-reader > img").unwrap();
-    let images_src = document.select(&css_selector)
-            .map(|element| element.value().attr("src").unwrap().to_string())
-            .collect();
+    pub async fn get_images_src(&self, chapter_url: &str) -> Res<Vec<String>> {
+        let source = find_source(chapter_url)?;
+        let client = self.create_client(source.as_ref());
+        source.fetch_images(&client, chapter_url).await
+    }
 
-    Ok(images_src)
-}
+    /// Saves every page of the chapter at `url` into `chapter_dir`, skipping the ones already there.
+    /// A page that keeps failing is reported and the others carry on, returns how many failed.
+    pub async fn download_chapter(&self, chapter_dir: &str, url: &str, mut progress: impl FnMut(PageProgress)) -> Res<u32> {
+        let mut threads = JoinSet::new();
+        let mut timer = Latency::new("download_chapter");
 
-pub async fn download_chapter(chapter_dir: &str, url: &str) -> Res<()> {
-    
-    let mut threads = Vec::new();
-    let mut timer = Latency::new("download_chapter");
-    {
         // Multithreads download_image
-        let client = create_client().await;
-        let response = client.get(url).send().await?;
-        let body = response.text().await.unwrap();
-        let document = Html::parse_document(&body);
-        let selector = Selector::parse(".container-chapter-reader > img").unwrap();
+        let source = find_source(url)?;
+        let client = self.create_client(source.as_ref());
+
+        let images = source.fetch_images(&client, url).await?;
+        progress(PageProgress::Found(images.len() as u32));
 
         // Each thread runs download_image_and_save()
-        for (i, element) in document.select(&selector).enumerate() {
+        for (i, src) in (0u32..).zip(images) {
             let client_clone = client.clone();
-            let src = element.value().attr("src").unwrap().to_string();
             let path = format!("{}/{}.jpeg", chapter_dir, i);
+            if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                progress(PageProgress::Saved(0));
+                continue;
+            }
 
-            threads.push(tokio::spawn(download_image_and_save(client_clone, src, path)));
+            threads.spawn(async move { (i, download_image_and_save(client_clone, src, path).await) });
         }
+
+        // Wait for all threads to finish, dropping the set aborts the others
+        let mut failed = 0;
+        while let Some(thread) = threads.join_next().await {
+            match thread? {
+                (_, Ok(bytes)) => progress(PageProgress::Saved(bytes)),
+                (page, Err(e)) => {
+                    failed += 1;
+                    progress(PageProgress::Failed(page, e));
+                }
+            }
+        }
+        timer.tick("done downloading + saving all images");
+        Ok(failed)
     }
+}
 
+fn find_source(url: &str) -> Res<Arc<dyn Source>> {
+    source::for_url(url).ok_or_else(|| AppError::BadRequest(format!("no source handles {url}")))
+}
 
-    // Wait for all threads to finish
-    for thread in threads {
-        thread.await.unwrap().unwrap();
+pub struct WebResult {
+    pub title: String,
+    pub chap_prefix: String,
+    pub last_updated: String,
+    pub meta: TitleMeta,
+    pub chapters: Vec<Chapter>,
+    pub cover: Bytes,
+}
+
+fn to_chapter(link: &ChapterLink, i: u32) -> Chapter {
+    let (number, volume) = Chapter::parse_label(&link.text);
+    Chapter {
+        t: link.text.clone(),
+        s: link.url.rsplit_once('/').map_or(link.url.as_str(), |(_, suffix)| suffix).to_string(),
+        i,
+        counted: false,
+        number,
+        volume,
+        released: link.released.clone(),
+        group: link.group.clone(),
+        language: link.language.clone(),
     }
-    timer.tick("done downloading + saving all images");
-    Ok(())
 }
 
-// Downloads image and saves it to path
-use tokio::{fs::File, io::AsyncWriteExt, task::JoinError};
-async fn download_image_and_save(client: Client, url: String, path: String) -> Res<()> {
-    let response = client.get(&url).send().await?;
-    let bytes = response.bytes().await?;
-    let mut file = File::create(path.clone()).await?;
-    file.write_all(&bytes).await?;
+/// What download_chapter reports while it runs
+pub enum PageProgress {
+    Found(u32), // number of pages, once the chapter page was scraped
+    Saved(u64), // bytes of one saved page, 0 if an earlier attempt already saved it
+    Failed(u32, AppError), // page index, still failing after retries
+}
 
-    Ok(())
-}
\ No newline at end of file
+// Downloads image and saves it to path, returns its size
+use tokio::task::JoinSet;
+async fn download_image_and_save(client: Client, url: String, path: String) -> Res<u64> {
+    // pages the reader already viewed through /proxy
+    let bytes = match cache::get(&url).await {
+        Some(image) => image.bytes,
+        None => http::get_bytes(&client, &url).await?,
+    };
+    storage::write_atomic(&path, &bytes).await?;
+    Ok(bytes.len() as u64)
+}